@@ -0,0 +1,106 @@
+// Throughput of many tiny jobs: the work-stealing `ThreadPool` against the design it replaced,
+// where every worker waited on one `Arc<Mutex<mpsc::Receiver<Job>>>`.
+// Run it with "cargo bench --bench throughput", it's a plain `main` (harness = false) and prints
+// jobs per second for a few pool sizes.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use hello::ThreadPool;
+
+const JOBS: usize = 1_000_000;
+const ROUNDS: usize = 3;
+
+// The chapter's original pool, minus the printing.
+struct ChannelPool {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+impl ChannelPool {
+    fn new(size: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || {
+                    loop {
+                        let message = receiver.lock().unwrap().recv();
+                        match message {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    }
+                })
+            })
+            .collect();
+        ChannelPool { workers, sender: Some(sender) }
+    }
+
+    fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+// Submits `JOBS` jobs that each bump a counter and waits until all of them ran. We take the best
+// of a few rounds to keep noise from other processes out.
+fn measure(execute: impl Fn(Box<dyn FnOnce() + Send>)) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let done = Arc::new(AtomicUsize::new(0));
+            let started = Instant::now();
+            for _ in 0..JOBS {
+                let done = Arc::clone(&done);
+                execute(Box::new(move || {
+                    done.fetch_add(1, Ordering::Relaxed);
+                }));
+            }
+            while done.load(Ordering::Relaxed) < JOBS {
+                thread::yield_now();
+            }
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn jobs_per_second(elapsed: Duration) -> f64 {
+    JOBS as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    println!("{JOBS} tiny jobs, best of {ROUNDS} rounds");
+    println!("{:>8} {:>18} {:>18} {:>8}", "workers", "mutex+channel", "work-stealing", "speedup");
+    for size in [1, 2, 4, 8] {
+        let channel_pool = ChannelPool::new(size);
+        let channel = measure(|job| channel_pool.execute(job));
+        drop(channel_pool);
+
+        let stealing_pool = ThreadPool::builder().size(size).build().unwrap();
+        let stealing = measure(|job| stealing_pool.execute(job).unwrap());
+        drop(stealing_pool);
+
+        println!(
+            "{size:>8} {:>13.0} j/s {:>13.0} j/s {:>7.2}x",
+            jobs_per_second(channel),
+            jobs_per_second(stealing),
+            channel.as_secs_f64() / stealing.as_secs_f64()
+        );
+    }
+}
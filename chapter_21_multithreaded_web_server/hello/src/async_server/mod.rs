@@ -0,0 +1,282 @@
+use std::io::{self, Read};
+use std::net::TcpListener as StdTcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+use crate::handler::Handler;
+use crate::http::response::{LAST_CHUNK, chunk_header};
+use crate::http::{Body, Limits, Request, RequestError, Response, StatusCode};
+use crate::server::{self, ConnectionLimits, ConnectionPermit, Shutdown, TimedStream};
+
+// The same server as `main`'s thread pool one, on tokio instead (`--mode async`, needs the
+// `async` feature). The difference is what a connection costs while it waits for its next
+// request: in the pool it holds a worker thread, here it's a task that takes up a few hundred
+// bytes until the socket has something for it. That makes thousands of idle keep-alive
+// connections no problem.
+//
+// Handlers are the same ones, and they're blocking code, so they run in `block_in_place`: the
+// runtime moves its other tasks off this thread while the handler works.
+
+// Serves `listener` until `shutdown` is triggered, then gives the open connections
+// `config.shutdown_timeout` to finish. Returns whether they all did, like
+// `ThreadPool::shutdown_timeout`.
+pub fn serve(
+    listener: StdTcpListener,
+    shutdown: &Shutdown,
+    config: Arc<Config>,
+    app: Arc<dyn Handler>,
+) -> io::Result<bool> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.workers)
+        .thread_name("hello-async")
+        .enable_all()
+        .build()?;
+    let finished = runtime.block_on(accept_loop(listener, shutdown, config, app));
+    // Whatever is still running after the timeout is abandoned, like the pool's detached workers.
+    runtime.shutdown_background();
+    finished
+}
+
+async fn accept_loop(
+    listener: StdTcpListener,
+    shutdown: &Shutdown,
+    config: Arc<Config>,
+    app: Arc<dyn Handler>,
+) -> io::Result<bool> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    // Idle connections wait on this as well as their socket, so they close as soon as we stop.
+    let (stop, stopping) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let limits = ConnectionLimits::new(config.max_connections, config.max_connections_per_ip);
+
+    loop {
+        let accepted = listener.accept().await;
+        // `Shutdown::trigger` wakes us up with a connection of its own.
+        if shutdown.is_triggered() {
+            break;
+        }
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            // Most likely we're out of file descriptors, give the open connections a moment to
+            // close some.
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {e}");
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        // Forget the ones that are done, or the set would grow with every connection.
+        while connections.try_join_next().is_some() {}
+        // Tasks are cheap, but each connection still holds a socket and some buffers.
+        let permit = match limits.acquire(Some(peer.ip())) {
+            Ok(permit) => permit,
+            Err(status) => {
+                connections.spawn(reject(stream, status));
+                continue;
+            }
+        };
+        connections.spawn(handle_connection(stream, permit, stopping.clone(), Arc::clone(&config), Arc::clone(&app)));
+    }
+
+    let _ = stop.send(true);
+    let drained = async { while connections.join_next().await.is_some() {} };
+    Ok(time::timeout(config.shutdown_timeout, drained).await.is_ok())
+}
+
+// Turns a connection away when it's over the limits, like `main`'s `reject_connection`.
+async fn reject(mut stream: TcpStream, status: StatusCode) {
+    let response = Response::new(status).with_header("Retry-After", "1").with_header("Connection", "close");
+    let _ = write_response(&mut stream, response, false, Duration::from_secs(1)).await;
+}
+
+// Keep-alive works like in the thread pool server: requests are read one after the other until
+// the client says to close, stays idle too long or we shut down.
+// `permit` counts the connection against the `ConnectionLimits` for as long as it's open.
+async fn handle_connection(
+    stream: TcpStream,
+    permit: ConnectionPermit,
+    mut stopping: watch::Receiver<bool>,
+    config: Arc<Config>,
+    app: Arc<dyn Handler>,
+) {
+    let remote_addr = stream.peer_addr().ok();
+    // The head and body of a response often go out in separate writes, see the thread pool
+    // server's `handle_connection`.
+    let _ = stream.set_nodelay(true);
+    let mut reader = BufReader::new(stream);
+    loop {
+        // Waiting for the first byte of the next request is free here, no need to poll.
+        tokio::select! {
+            ready = reader.fill_buf() => match ready {
+                Ok(buffer) if !buffer.is_empty() => {}
+                _ => return,
+            },
+            _ = time::sleep(config.keep_alive_timeout) => return,
+            _ = stopping.wait_for(|stopping| *stopping) => return,
+        }
+
+        // A slow client only costs us a task, so the whole-request deadline is the only limit,
+        // there's no separate per-read timeout.
+        let deadline = Instant::now() + config.request_timeout;
+        let read = time::timeout_at(deadline, read_request(&mut reader, &config.limits)).await;
+        let mut request = match read.unwrap_or(Err(RequestError::TimedOut)) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => {
+                if let Some(status) = e.status() {
+                    let response = Response::new(status).with_header("Connection", "close");
+                    let _ = write_response(reader.get_mut(), response, false, config.write_timeout).await;
+                }
+                return;
+            }
+        };
+
+        request.remote_addr = remote_addr;
+        let mut response = task::block_in_place(|| app.handle(&request));
+
+        // WebSocket handlers are blocking code too, so an upgraded connection leaves the runtime
+        // and becomes a std socket on a thread of its own, like in the thread pool server.
+        if let Some(upgrade) = response.upgrade.take() {
+            if write_response(reader.get_mut(), response, false, config.write_timeout).await.is_err() {
+                return;
+            }
+            let buffered = reader.buffer().to_vec();
+            let Ok(stream) = reader.into_inner().into_std() else {
+                return;
+            };
+            if stream.set_nonblocking(false).is_err() || stream.set_write_timeout(Some(config.write_timeout)).is_err() {
+                return;
+            }
+            let connection = TimedStream::new(stream, server::UPGRADED_IDLE_TIMEOUT);
+            server::hand_over(buffered, Box::new(connection), permit, upgrade);
+            return;
+        }
+
+        let keep_alive = request.keep_alive() && !*stopping.borrow();
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        let response = response.with_header("Connection", connection);
+        let head_request = request.method == "HEAD";
+        if write_response(reader.get_mut(), response, head_request, config.write_timeout).await.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+// Collects the request line and headers, up to and including the empty line after them, and
+// lets the blocking parser at that. Then reads the body.
+async fn read_request(reader: &mut BufReader<TcpStream>, limits: &Limits) -> Result<Option<Request>, RequestError> {
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        // One byte more than we allow, to tell "exactly at the limit" from "over it".
+        let allowed = (limits.max_header_bytes + 1).saturating_sub(start) as u64;
+        let read = (&mut *reader).take(allowed).read_until(b'\n', &mut head).await?;
+        if head.len() > limits.max_header_bytes {
+            return Err(RequestError::HeadersTooLarge);
+        }
+        let is_blank = |bytes: &[u8]| bytes.iter().all(u8::is_ascii_whitespace);
+        if read == 0 {
+            if is_blank(&head) {
+                return Ok(None);
+            }
+            return Err(RequestError::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        // Blank lines before the request line don't end anything, see `Request::read_head`.
+        if is_blank(&head[start..]) && !is_blank(&head[..start]) {
+            break;
+        }
+    }
+
+    let Some(mut request) = Request::read_head(&mut &head[..], limits)? else {
+        return Ok(None);
+    };
+    let length = request.body_len(limits)?;
+    if length > 0 {
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).await?;
+    }
+    Ok(Some(request))
+}
+
+// `Response::write_to` for a tokio socket, or `write_head_to` for a HEAD request. No single write
+// may take longer than `timeout`.
+async fn write_response(stream: &mut TcpStream, response: Response, head_request: bool, timeout: Duration) -> io::Result<()> {
+    let (head, body) = response.into_head();
+    let body = if head_request { Body::Empty } else { body };
+    match body {
+        Body::Empty => write(stream, &head, timeout).await,
+        // One write for small responses, that's what most of them are.
+        Body::Bytes(mut bytes) => {
+            let mut whole = head;
+            whole.append(&mut bytes);
+            write(stream, &whole, timeout).await
+        }
+        Body::File { file, len } => {
+            write(stream, &head, timeout).await?;
+            let mut file = tokio::fs::File::from_std(file).take(len);
+            let mut buffer = vec![0; 64 * 1024];
+            let mut copied = 0;
+            loop {
+                let read = file.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                write(stream, &buffer[..read], timeout).await?;
+                copied += read as u64;
+            }
+            // The file shrank since we looked at its length.
+            if copied < len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            Ok(())
+        }
+        Body::Stream { reader, len } => {
+            write(stream, &head, timeout).await?;
+            // A blocking reader, so each read goes through `block_in_place` like the handler.
+            let mut reader = reader.take(len);
+            let mut buffer = vec![0; 64 * 1024];
+            let mut copied = 0;
+            loop {
+                let read = task::block_in_place(|| reader.read(&mut buffer))?;
+                if read == 0 {
+                    break;
+                }
+                write(stream, &buffer[..read], timeout).await?;
+                copied += read as u64;
+            }
+            if copied < len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            Ok(())
+        }
+        Body::Chunked(mut chunks) => {
+            write(stream, &head, timeout).await?;
+            // Producing a chunk may block, like the handler.
+            while let Some(chunk) = task::block_in_place(|| chunks.next()) {
+                let chunk = chunk?;
+                if chunk.is_empty() {
+                    continue;
+                }
+                let mut framed = chunk_header(chunk.len());
+                framed.extend_from_slice(&chunk);
+                framed.extend_from_slice(b"\r\n");
+                write(stream, &framed, timeout).await?;
+            }
+            write(stream, LAST_CHUNK, timeout).await
+        }
+    }
+}
+
+async fn write(stream: &mut TcpStream, bytes: &[u8], timeout: Duration) -> io::Result<()> {
+    time::timeout(timeout, stream.write_all(bytes))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))
+}
@@ -0,0 +1,205 @@
+// A small load generator, so we can see what pool size and keep-alive do to this server without
+// installing wrk:
+//
+// cargo run --release --bin hello-bench -- --connections 100 --duration 10 http://127.0.0.1:7878/
+//
+// Every connection gets a thread of its own that sends GET requests one after the other for the
+// whole run, then we add up what they saw.
+use std::collections::BTreeMap;
+use std::env;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hello::client::Client;
+
+const USAGE: &str = "\
+Usage: hello-bench [OPTIONS] <URL>
+
+Sends GET requests to URL (http://host:port/path) from several connections at once and reports
+requests per second, latency percentiles and errors.
+
+Options:
+  -c, --connections <N>   connections open at the same time (default 50)
+  -d, --duration <SECS>   how long to keep sending requests (default 10)
+  -t, --timeout <SECS>    longest a request may take before it counts as an error (default 5)
+      --no-keep-alive     open a new connection for every request
+  -h, --help              print this and exit";
+
+struct Options {
+    addr: SocketAddr,
+    // What goes in the request line, e.g. "/index.html".
+    path: String,
+    url: String,
+    connections: usize,
+    duration: Duration,
+    timeout: Duration,
+    keep_alive: bool,
+}
+
+impl Options {
+    // Same idea as the server's `Config::build`, without the environment variables.
+    fn build(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        args.next();
+        let mut url = None;
+        let (mut connections, mut duration, mut timeout, mut keep_alive) = (50, 10, 5, true);
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| -> Result<u64, String> {
+                let value = args.next().ok_or_else(|| format!("{name} needs a value"))?;
+                match value.parse() {
+                    Ok(0) | Err(_) => Err(format!("invalid value {value:?} for {name}")),
+                    Ok(value) => Ok(value),
+                }
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Err(String::new()),
+                "-c" | "--connections" => connections = value(&arg)?,
+                "-d" | "--duration" => duration = value(&arg)?,
+                "-t" | "--timeout" => timeout = value(&arg)?,
+                "--no-keep-alive" => keep_alive = false,
+                flag if flag.starts_with('-') => return Err(format!("unknown option {flag}, see --help")),
+                _ if url.is_some() => return Err(String::from("only one URL, please")),
+                _ => url = Some(arg),
+            }
+        }
+        let url = url.ok_or("missing the URL to send requests to")?;
+        let (addr, path) = parse_url(&url)?;
+        Ok(Options {
+            addr,
+            path,
+            url,
+            connections: connections as usize,
+            duration: Duration::from_secs(duration),
+            timeout: Duration::from_secs(timeout),
+            keep_alive,
+        })
+    }
+}
+
+// "http://localhost:7878/index.html" -> (127.0.0.1:7878, "/index.html"). The port defaults to 80.
+fn parse_url(url: &str) -> Result<(SocketAddr, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("{url} isn't an http:// URL"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], rest[slash..].to_string()),
+        None => (rest, String::from("/")),
+    };
+    let authority = if authority.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+        authority.to_string()
+    } else {
+        format!("{authority}:80")
+    };
+    let addr = authority
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("can't resolve {authority}"))?;
+    Ok((addr, path))
+}
+
+// What one connection saw.
+#[derive(Default)]
+struct Results {
+    // Of every request that got a response, in microseconds.
+    latencies: Vec<u64>,
+    statuses: BTreeMap<u16, u64>,
+    // Requests that got no response: refused connections, timeouts, resets...
+    errors: u64,
+    body_bytes: u64,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.errors += other.errors;
+        self.body_bytes += other.body_bytes;
+    }
+}
+
+fn run_connection(options: &Options, until: Instant) -> Results {
+    let mut results = Results::default();
+    let mut client = Client::new(options.addr).expect("the address was resolved already");
+    client.set_timeout(options.timeout);
+    let headers: &[(&str, &str)] = if options.keep_alive { &[] } else { &[("Connection", "close")] };
+    while Instant::now() < until {
+        let start = Instant::now();
+        match client.request("GET", &options.path, headers, &[]) {
+            Ok(response) => {
+                results.latencies.push(start.elapsed().as_micros() as u64);
+                *results.statuses.entry(response.status).or_default() += 1;
+                results.body_bytes += response.body.len() as u64;
+            }
+            Err(_) => {
+                results.errors += 1;
+                // Don't spin when the server is down, every attempt would fail right away.
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+    results
+}
+
+fn main() {
+    let options = Options::build(env::args()).unwrap_or_else(|err| {
+        if err.is_empty() {
+            println!("{USAGE}");
+            process::exit(0);
+        }
+        eprintln!("Problem parsing arguments: {err}");
+        process::exit(2);
+    });
+
+    println!(
+        "Sending requests to {} for {:?} from {} connections ({}).",
+        options.url,
+        options.duration,
+        options.connections,
+        if options.keep_alive { "keep-alive" } else { "a new connection per request" }
+    );
+    let started = Instant::now();
+    let until = started + options.duration;
+    let mut results = Results::default();
+    thread::scope(|scope| {
+        let connections: Vec<_> = (0..options.connections)
+            .map(|_| scope.spawn(|| run_connection(&options, until)))
+            .collect();
+        for connection in connections {
+            results.merge(connection.join().unwrap());
+        }
+    });
+    let elapsed = started.elapsed();
+
+    report(&results, elapsed);
+}
+
+fn report(results: &Results, elapsed: Duration) {
+    let mut latencies = results.latencies.clone();
+    latencies.sort_unstable();
+    let responses = latencies.len();
+    let per_second = responses as f64 / elapsed.as_secs_f64();
+    println!();
+    println!("Requests:     {responses} in {elapsed:.2?}, {per_second:.1} requests/s");
+    println!("Transfer:     {:.2} MB of bodies", results.body_bytes as f64 / 1_000_000.0);
+    if let (Some(&fastest), Some(&slowest)) = (latencies.first(), latencies.last()) {
+        // The nearest-rank percentile.
+        let percentile = |p: f64| latencies[((p / 100.0 * responses as f64).ceil() as usize).clamp(1, responses) - 1];
+        let ms = |micros: u64| format!("{:.2}ms", micros as f64 / 1000.0);
+        println!(
+            "Latency:      min {}, p50 {}, p90 {}, p99 {}, p99.9 {}, max {}",
+            ms(fastest),
+            ms(percentile(50.0)),
+            ms(percentile(90.0)),
+            ms(percentile(99.0)),
+            ms(percentile(99.9)),
+            ms(slowest)
+        );
+    }
+    let statuses: Vec<String> = results.statuses.iter().map(|(status, count)| format!("{status}: {count}")).collect();
+    println!("Status codes: {}", if statuses.is_empty() { String::from("-") } else { statuses.join(", ") });
+    println!("Errors:       {} (requests that got no response)", results.errors);
+}
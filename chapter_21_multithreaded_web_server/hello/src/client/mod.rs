@@ -0,0 +1,370 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::http::Headers;
+
+// A small blocking HTTP/1.1 client, the other end of our server. Enough for tests and tools to
+// talk to a real server without curl:
+//
+// let mut client = Client::new("127.0.0.1:7878")?;
+// let response = client.get("/")?;
+// assert_eq!(response.status, 200);
+//
+// Like a browser it keeps the connection open between requests, and opens a new one when the
+// server closed it.
+pub struct Client {
+    addr: SocketAddr,
+    // `None` until the first request, and after the server said it's closing.
+    connection: Option<BufReader<TcpStream>>,
+    timeout: Duration,
+    connections_opened: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientResponse {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    // Whether the server keeps the connection open after this response.
+    keep_alive: bool,
+}
+
+impl ClientResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    // The body as text, for assertions. Invalid UTF-8 becomes U+FFFD.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    // Reads one response off `reader`. `head_request` because the answer to a HEAD request has
+    // headers describing a body that never comes.
+    pub fn read_from<R: BufRead>(reader: &mut R, head_request: bool) -> Result<ClientResponse, ClientError> {
+        let ResponseHead { status, reason, headers, mut keep_alive } = ResponseHead::read_from(reader)?;
+        let body = match BodyLength::of(status, &headers, head_request)? {
+            BodyLength::Empty => Vec::new(),
+            BodyLength::Chunked => {
+                let mut body = Vec::new();
+                for chunk in Chunks::new(reader) {
+                    body.extend_from_slice(&chunk?);
+                }
+                body
+            }
+            // The buffer grows with what actually arrives, not with what `Content-Length` claims.
+            BodyLength::Exactly(length) => {
+                let mut body = Vec::new();
+                reader.by_ref().take(length).read_to_end(&mut body)?;
+                if (body.len() as u64) < length {
+                    return Err(ClientError::Io(io::ErrorKind::UnexpectedEof.into()));
+                }
+                body
+            }
+            BodyLength::UntilClose => {
+                let mut body = Vec::new();
+                reader.read_to_end(&mut body)?;
+                keep_alive = false;
+                body
+            }
+        };
+        Ok(ClientResponse { status, reason, headers, body, keep_alive })
+    }
+}
+
+// The status line and headers of a response. The proxy reads these itself and streams the body
+// on instead of collecting it.
+pub(crate) struct ResponseHead {
+    pub(crate) status: u16,
+    pub(crate) reason: String,
+    pub(crate) headers: Headers,
+    // What the headers and the version say, a body that runs until the close can still change it.
+    pub(crate) keep_alive: bool,
+}
+
+impl ResponseHead {
+    pub(crate) fn read_from<R: BufRead>(reader: &mut R) -> Result<ResponseHead, ClientError> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(ClientError::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        // "HTTP/1.1 404 Not Found"
+        let mut parts = line.trim_end().splitn(3, ' ');
+        let (version, status, reason) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(version), Some(status), reason) if version.starts_with("HTTP/1.") => {
+                let status = status.parse().map_err(|_| ClientError::Malformed("invalid status code"))?;
+                (version.to_string(), status, reason.unwrap_or("").to_string())
+            }
+            _ => return Err(ClientError::Malformed("malformed status line")),
+        };
+
+        let mut headers = Headers::default();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(ClientError::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':').ok_or(ClientError::Malformed("malformed header"))?;
+            headers.append(name.trim(), value.trim());
+        }
+
+        let connection = headers.get("Connection").unwrap_or("").to_ascii_lowercase();
+        let keep_alive = if version == "HTTP/1.0" {
+            connection.contains("keep-alive")
+        } else {
+            !connection.contains("close")
+        };
+        Ok(ResponseHead { status, reason, headers, keep_alive })
+    }
+}
+
+// Where the body after a response head ends.
+pub(crate) enum BodyLength {
+    Empty,
+    Chunked,
+    Exactly(u64),
+    // No length at all: the body is everything until the server closes the connection.
+    UntilClose,
+}
+
+impl BodyLength {
+    pub(crate) fn of(status: u16, headers: &Headers, head_request: bool) -> Result<BodyLength, ClientError> {
+        if head_request || matches!(status, 100..=199 | 204 | 304) {
+            Ok(BodyLength::Empty)
+        } else if headers.get("Transfer-Encoding").is_some_and(|coding| coding.eq_ignore_ascii_case("chunked")) {
+            Ok(BodyLength::Chunked)
+        } else if let Some(length) = headers.get("Content-Length") {
+            let length = length.parse().map_err(|_| ClientError::Malformed("invalid Content-Length"))?;
+            Ok(BodyLength::Exactly(length))
+        } else {
+            Ok(BodyLength::UntilClose)
+        }
+    }
+}
+
+// The chunks of a chunked body, one at a time. Chunk sizes are in hex, each chunk ends with CRLF,
+// and a 0 size ends the body (optionally followed by trailers, which we skip).
+// The size is whatever the server says it is, so a big chunk comes out in pieces of at most
+// `MAX_PIECE` bytes instead of being allocated (and buffered) whole.
+pub(crate) struct Chunks<R> {
+    reader: R,
+    // What's left of the chunk we're in the middle of.
+    remaining: u64,
+    done: bool,
+}
+
+const MAX_PIECE: u64 = 64 * 1024;
+
+impl<R: BufRead> Chunks<R> {
+    pub(crate) fn new(reader: R) -> Chunks<R> {
+        Chunks { reader, remaining: 0, done: false }
+    }
+
+    fn next_piece(&mut self) -> Result<Option<Vec<u8>>, ClientError> {
+        if self.remaining == 0 {
+            let mut line = String::new();
+            self.reader.read_line(&mut line)?;
+            // Chunk extensions come after a ';', nobody uses them.
+            let size = line.trim_end().split(';').next().unwrap_or("");
+            let size = u64::from_str_radix(size.trim(), 16).map_err(|_| ClientError::Malformed("invalid chunk size"))?;
+            if size == 0 {
+                loop {
+                    line.clear();
+                    if self.reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                        return Ok(None);
+                    }
+                }
+            }
+            self.remaining = size;
+        }
+        let mut piece = vec![0; self.remaining.min(MAX_PIECE) as usize];
+        self.reader.read_exact(&mut piece)?;
+        self.remaining -= piece.len() as u64;
+        if self.remaining == 0 {
+            let mut crlf = [0; 2];
+            self.reader.read_exact(&mut crlf)?;
+            if &crlf != b"\r\n" {
+                return Err(ClientError::Malformed("chunk without CRLF"));
+            }
+        }
+        Ok(Some(piece))
+    }
+}
+
+impl<R: BufRead> Iterator for Chunks<R> {
+    type Item = Result<Vec<u8>, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let chunk = self.next_piece().transpose();
+        // After the last chunk or an error there's nothing more we can make sense of.
+        self.done = !matches!(chunk, Some(Ok(_)));
+        chunk
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    // The server's response doesn't make sense.
+    Malformed(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Malformed(message) => write!(f, "bad response: {message}"),
+            ClientError::Io(e) => write!(f, "request failed: {e}"),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            ClientError::Malformed(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
+
+impl Client {
+    pub fn new(addr: impl ToSocketAddrs) -> io::Result<Client> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"))?;
+        Ok(Client { addr, connection: None, timeout: Duration::from_secs(10), connections_opened: 0 })
+    }
+
+    // The longest connecting, or any single read or write, may take.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        self.connection = None;
+    }
+
+    // How many connections we've opened so far, to check that keep-alive works.
+    pub fn connections_opened(&self) -> usize {
+        self.connections_opened
+    }
+
+    pub fn get(&mut self, path: &str) -> Result<ClientResponse, ClientError> {
+        self.request("GET", path, &[], &[])
+    }
+
+    // Sends one request and waits for its response. `Host` and `Content-Length` are added for
+    // us.
+    pub fn request(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<ClientResponse, ClientError> {
+        let mut request = format!("{method} {path} HTTP/1.1\r\nHost: {}\r\n", self.addr);
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !body.is_empty() || matches!(method, "POST" | "PUT" | "PATCH") {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+
+        // A reused connection may have been closed by the server in the meantime (its
+        // keep-alive timeout ran out), then we try once more on a new one. The server may have
+        // got the request before it closed the connection, so we only do that for methods that
+        // are idempotent: sending them twice does what sending them once does. A failed POST
+        // is the caller's to retry or not.
+        let reused = self.connection.is_some();
+        let idempotent = matches!(method, "GET" | "HEAD" | "OPTIONS" | "PUT" | "DELETE");
+        match self.send(&request, method == "HEAD") {
+            Err(ClientError::Io(_)) if reused && idempotent => self.send(&request, method == "HEAD"),
+            result => result,
+        }
+    }
+
+    fn send(&mut self, request: &[u8], head_request: bool) -> Result<ClientResponse, ClientError> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => {
+                let stream = TcpStream::connect_timeout(&self.addr, self.timeout)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                // Every request is a single write, there's nothing for Nagle's algorithm to
+                // gather.
+                stream.set_nodelay(true)?;
+                self.connections_opened += 1;
+                self.connection.insert(BufReader::new(stream))
+            }
+        };
+        let result = connection
+            .get_mut()
+            .write_all(request)
+            .map_err(ClientError::from)
+            .and_then(|()| ClientResponse::read_from(connection, head_request));
+        // After an error we can't tell where the next response would start.
+        if !result.as_ref().is_ok_and(|response| response.keep_alive) {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_chunked_and_close_delimited_bodies() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5;ext=1\r\nworld\r\n0\r\n\r\n\
+HTTP/1.0 404 Not Found\r\n\r\nuntil the end";
+        let mut reader = raw.as_bytes();
+
+        let first = ClientResponse::read_from(&mut reader, false).unwrap();
+        assert_eq!((first.status, first.reason.as_str()), (200, "OK"));
+        assert_eq!(first.text(), "hello world");
+        assert!(first.keep_alive);
+
+        let second = ClientResponse::read_from(&mut reader, false).unwrap();
+        assert_eq!(second.status, 404);
+        assert_eq!(second.text(), "until the end");
+        assert!(!second.keep_alive);
+    }
+
+    #[test]
+    fn huge_chunks_come_in_pieces() {
+        let big = format!("{:X}\r\n{}\r\n0\r\n\r\n", 100_000, "a".repeat(100_000));
+        let pieces: Vec<usize> = Chunks::new(big.as_bytes()).map(|piece| piece.unwrap().len()).collect();
+        assert_eq!(pieces, [65_536, 34_464]);
+
+        // A size nobody could allocate is only a problem once the bytes don't come.
+        let mut lying = Chunks::new("FFFFFFFFFFFFFFFF\r\nabc".as_bytes());
+        assert!(matches!(lying.next(), Some(Err(ClientError::Io(_)))));
+        assert!(lying.next().is_none());
+    }
+
+    #[test]
+    fn a_content_length_that_never_comes_is_an_error() {
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\nabc";
+        let result = ClientResponse::read_from(&mut raw.as_bytes(), false);
+        assert!(matches!(result, Err(ClientError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+}
@@ -0,0 +1,410 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::http::Limits;
+
+// Everything about the server that used to be hardcoded in `main`. Same idea as minigrep's
+// `Config`, except every setting can come from a command-line flag or an environment variable,
+// the flag wins if there are both:
+//
+// hello --host 0.0.0.0 --port 8080 --workers 8 --root ./public
+// HELLO_PORT=0 hello
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub host: String,
+    // 0 lets the OS pick a free port, `main` prints the one it got.
+    pub port: u16,
+    // The most worker threads the pool may start.
+    pub workers: usize,
+    // Where hello.html, 404.html and the templates they include are.
+    pub root: PathBuf,
+    // How long an idle keep-alive connection stays open.
+    pub keep_alive_timeout: Duration,
+    // How long the requests in flight get to finish once we're shutting down.
+    pub shutdown_timeout: Duration,
+    // The longest a single read or write on a connection may block.
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    // How long a client gets to send a whole request, from its first byte to its last.
+    pub request_timeout: Duration,
+    pub limits: Limits,
+    // Origins allowed to call us from a browser, `*` for any. No CORS headers at all if empty.
+    pub cors_origins: Vec<String>,
+    // If set, every request needs "Authorization: Bearer <token>".
+    pub auth_token: Option<String>,
+    // PEM files for HTTPS, both or neither. Needs the `tls` feature.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // Each open WebSocket has a thread of its own, outside the pool.
+    pub max_websockets: usize,
+    pub mode: Mode,
+    // Requests under a path prefix that are passed on to other servers.
+    pub proxy: Option<ProxyRoute>,
+    // Open connections, in total and from any one client IP. No cap if `None`.
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    // Requests per second from any one client IP, in bursts of up to as many. No limit if `None`.
+    pub rate_limit: Option<u32>,
+    // Development mode: templates are read again when they change on disk.
+    pub dev: bool,
+    // Listen on this Unix domain socket instead of `host` and `port`, for a proxy or sidecar on
+    // the same machine.
+    pub unix: Option<PathBuf>,
+    // Permission bits for the socket file, who may connect to it.
+    pub unix_mode: u32,
+}
+
+// How connections are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    // One pool worker per connection, for as long as the connection is open.
+    #[default]
+    Threads,
+    // Tokio tasks on a few threads, an idle connection costs next to nothing. Needs the `async`
+    // feature.
+    Async,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(mode: &str) -> Result<Mode, ()> {
+        match mode {
+            "threads" => Ok(Mode::Threads),
+            "async" => Ok(Mode::Async),
+            _ => Err(()),
+        }
+    }
+}
+
+// "/api=10.0.0.5:8080,10.0.0.6:8080": requests for /api and below go to those two, in turn.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+}
+
+impl ProxyRoute {
+    // Whole path segments only, "/api" is no prefix of "/apis".
+    pub fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?']),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for ProxyRoute {
+    type Err = ();
+
+    fn from_str(route: &str) -> Result<ProxyRoute, ()> {
+        let (prefix, upstreams) = route.split_once('=').ok_or(())?;
+        let upstreams: Vec<String> = upstreams.split(',').map(str::trim).map(String::from).collect();
+        if !prefix.starts_with('/') || upstreams.iter().any(|upstream| upstream.is_empty()) {
+            return Err(());
+        }
+        Ok(ProxyRoute { prefix: prefix.to_string(), upstreams })
+    }
+}
+
+pub const USAGE: &str = "\
+Usage: hello [OPTIONS]
+
+Options (environment variable in brackets):
+  --host <HOST>                 address to listen on [HELLO_HOST] (default 127.0.0.1)
+  --port <PORT>                 port to listen on, 0 picks a free one [HELLO_PORT] (default 7878)
+  --workers <N>                 most worker threads [HELLO_WORKERS] (default 16)
+  --root <DIR>                  directory with the HTML files [HELLO_ROOT] (default .)
+  --keep-alive-timeout <SECS>   idle keep-alive connections are closed after this [HELLO_KEEP_ALIVE_TIMEOUT] (default 5)
+  --shutdown-timeout <SECS>     time requests get to finish on shutdown [HELLO_SHUTDOWN_TIMEOUT] (default 10)
+  --read-timeout <SECS>         longest a single read may block [HELLO_READ_TIMEOUT] (default 5)
+  --write-timeout <SECS>        longest a single write may block [HELLO_WRITE_TIMEOUT] (default 5)
+  --request-timeout <SECS>      time a client gets to send a whole request [HELLO_REQUEST_TIMEOUT] (default 10)
+  --max-header-size <BYTES>     request line and headers together [HELLO_MAX_HEADER_SIZE] (default 8192)
+  --max-headers <N>             headers per request [HELLO_MAX_HEADERS] (default 100)
+  --cors-origin <ORIGINS>       comma-separated origins allowed to call us from a browser, * for any [HELLO_CORS_ORIGIN]
+  --auth-token <TOKEN>          require \"Authorization: Bearer <TOKEN>\" on every request [HELLO_AUTH_TOKEN]
+  --tls-cert <FILE>             serve HTTPS with this PEM certificate chain [HELLO_TLS_CERT]
+  --tls-key <FILE>              the certificate's PEM private key [HELLO_TLS_KEY]
+  --max-websockets <N>          most WebSocket connections open at once [HELLO_MAX_WEBSOCKETS] (default 64)
+  --mode <threads|async>        thread pool, or tokio with the async feature [HELLO_MODE] (default threads)
+  --proxy <PREFIX=HOST:PORT,..> pass requests under PREFIX on to these servers, in turn [HELLO_PROXY]
+  --max-connections <N>         most connections open at once [HELLO_MAX_CONNECTIONS]
+  --max-connections-per-ip <N>  most connections open at once from one client [HELLO_MAX_CONNECTIONS_PER_IP]
+  --rate-limit <N>              requests per second from one client [HELLO_RATE_LIMIT]
+  --dev                         reload templates when they change [HELLO_DEV=true]
+  --unix <PATH>                 listen on a Unix domain socket instead of --host and --port [HELLO_UNIX]
+  --unix-mode <OCTAL>           permissions of the socket file [HELLO_UNIX_MODE] (default 660)
+  --help                        print this and exit";
+
+// Every option as (flag, environment variable).
+const OPTIONS: [(&str, &str); 24] = [
+    ("--host", "HELLO_HOST"),
+    ("--port", "HELLO_PORT"),
+    ("--workers", "HELLO_WORKERS"),
+    ("--root", "HELLO_ROOT"),
+    ("--keep-alive-timeout", "HELLO_KEEP_ALIVE_TIMEOUT"),
+    ("--shutdown-timeout", "HELLO_SHUTDOWN_TIMEOUT"),
+    ("--read-timeout", "HELLO_READ_TIMEOUT"),
+    ("--write-timeout", "HELLO_WRITE_TIMEOUT"),
+    ("--request-timeout", "HELLO_REQUEST_TIMEOUT"),
+    ("--max-header-size", "HELLO_MAX_HEADER_SIZE"),
+    ("--max-headers", "HELLO_MAX_HEADERS"),
+    ("--cors-origin", "HELLO_CORS_ORIGIN"),
+    ("--auth-token", "HELLO_AUTH_TOKEN"),
+    ("--tls-cert", "HELLO_TLS_CERT"),
+    ("--tls-key", "HELLO_TLS_KEY"),
+    ("--max-websockets", "HELLO_MAX_WEBSOCKETS"),
+    ("--mode", "HELLO_MODE"),
+    ("--proxy", "HELLO_PROXY"),
+    ("--max-connections", "HELLO_MAX_CONNECTIONS"),
+    ("--max-connections-per-ip", "HELLO_MAX_CONNECTIONS_PER_IP"),
+    ("--rate-limit", "HELLO_RATE_LIMIT"),
+    ("--dev", "HELLO_DEV"),
+    ("--unix", "HELLO_UNIX"),
+    ("--unix-mode", "HELLO_UNIX_MODE"),
+];
+
+// Options that are on or off, they don't take a value on the command line.
+const SWITCHES: [&str; 1] = ["--dev"];
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    // `--help` was passed, not really an error but `main` has to stop either way.
+    HelpRequested,
+    UnknownFlag(String),
+    MissingValue(String),
+    // `name` is the flag or environment variable the value came from.
+    InvalidValue { name: String, value: String },
+    // An option that's useless without another one, like a certificate without its key.
+    Requires { flag: String, other: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::HelpRequested => write!(f, "{USAGE}"),
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown option {flag}, see --help"),
+            ConfigError::MissingValue(flag) => write!(f, "{flag} needs a value"),
+            ConfigError::InvalidValue { name, value } => write!(f, "invalid value {value:?} for {name}"),
+            ConfigError::Requires { flag, other } => write!(f, "{flag} needs {other} as well"),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            host: String::from("127.0.0.1"),
+            port: 7878,
+            workers: 16,
+            root: PathBuf::from("."),
+            keep_alive_timeout: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(5),
+            write_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+            limits: Limits::default(),
+            cors_origins: Vec::new(),
+            auth_token: None,
+            tls_cert: None,
+            tls_key: None,
+            max_websockets: 64,
+            mode: Mode::default(),
+            proxy: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            rate_limit: None,
+            dev: false,
+            unix: None,
+            unix_mode: 0o660,
+        }
+    }
+}
+
+impl Config {
+    // `args` is `env::args()` (so the first item is the program name) and `env` looks up an
+    // environment variable, normally `|name| env::var(name).ok()`. Taking a function instead of
+    // reading the environment here keeps the tests from stepping on each other.
+    pub fn build(
+        mut args: impl Iterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        args.next();
+        let mut config = Config::default();
+
+        // Environment first, so the flags can override it.
+        for (flag, var) in OPTIONS {
+            if let Some(value) = env(var) {
+                config.set(flag, var, value)?;
+            }
+        }
+
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                return Err(ConfigError::HelpRequested);
+            }
+            // Both "--port 8080" and "--port=8080" work.
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            if !OPTIONS.iter().any(|(known, _)| *known == flag) {
+                return Err(ConfigError::UnknownFlag(flag));
+            }
+            let value = if SWITCHES.contains(&flag.as_str()) { value.or(Some(String::from("true"))) } else { value };
+            let value = match value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(ConfigError::MissingValue(flag)),
+            };
+            config.set(&flag, &flag, value)?;
+        }
+
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(_), None) => Err(ConfigError::Requires { flag: "--tls-cert".into(), other: "--tls-key".into() }),
+            (None, Some(_)) => Err(ConfigError::Requires { flag: "--tls-key".into(), other: "--tls-cert".into() }),
+            _ => Ok(config),
+        }
+    }
+
+    // `flag` says which setting it is, `name` is what we call it in errors: the flag or the
+    // environment variable, wherever the value came from.
+    fn set(&mut self, flag: &str, name: &str, value: String) -> Result<(), ConfigError> {
+        match flag {
+            "--host" => self.host = value,
+            "--port" => self.port = parse(name, &value)?,
+            "--workers" => self.workers = parse_nonzero(name, &value)?,
+            "--root" => self.root = PathBuf::from(value),
+            "--keep-alive-timeout" => self.keep_alive_timeout = parse_secs(name, &value)?,
+            "--shutdown-timeout" => self.shutdown_timeout = parse_secs(name, &value)?,
+            "--read-timeout" => self.read_timeout = parse_secs(name, &value)?,
+            "--write-timeout" => self.write_timeout = parse_secs(name, &value)?,
+            "--request-timeout" => self.request_timeout = parse_secs(name, &value)?,
+            "--max-header-size" => self.limits.max_header_bytes = parse_nonzero(name, &value)?,
+            "--max-headers" => self.limits.max_headers = parse_nonzero(name, &value)?,
+            "--cors-origin" => {
+                self.cors_origins = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "--auth-token" => self.auth_token = Some(value),
+            "--tls-cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "--tls-key" => self.tls_key = Some(PathBuf::from(value)),
+            "--max-websockets" => self.max_websockets = parse(name, &value)?,
+            "--mode" => self.mode = parse(name, &value)?,
+            "--proxy" => self.proxy = Some(parse(name, &value)?),
+            "--max-connections" => self.max_connections = Some(parse_nonzero(name, &value)?),
+            "--max-connections-per-ip" => self.max_connections_per_ip = Some(parse_nonzero(name, &value)?),
+            "--rate-limit" => self.rate_limit = Some(parse_nonzero(name, &value)?),
+            "--dev" => self.dev = parse(name, &value)?,
+            "--unix" => self.unix = Some(PathBuf::from(value)),
+            // Written like chmod takes it, 660 and not 432.
+            "--unix-mode" => {
+                self.unix_mode = match u32::from_str_radix(&value, 8) {
+                    Ok(mode) if mode <= 0o777 => mode,
+                    _ => return Err(ConfigError::InvalidValue { name: name.to_string(), value }),
+                }
+            }
+            _ => return Err(ConfigError::UnknownFlag(flag.to_string())),
+        }
+        Ok(())
+    }
+
+    // What to hand to `TcpListener::bind`.
+    pub fn address(&self) -> String {
+        // An IPv6 address needs brackets around it before the port.
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+// A pool without workers can't serve anything, a request needs at least one header byte, and a
+// limit of 0 would turn everyone away.
+fn parse_nonzero<T: FromStr + Default + PartialEq>(name: &str, value: &str) -> Result<T, ConfigError> {
+    match parse(name, value)? {
+        zero if zero == T::default() => {
+            Err(ConfigError::InvalidValue { name: name.to_string(), value: value.to_string() })
+        }
+        number => Ok(number),
+    }
+}
+
+// A timeout of 0 would mean "no timeout" to the socket, so we don't allow it.
+fn parse_secs(name: &str, value: &str) -> Result<Duration, ConfigError> {
+    parse_nonzero(name, value).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        ["hello"].iter().chain(args).map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn flags_override_environment() {
+        let env = |name: &str| match name {
+            "HELLO_PORT" => Some(String::from("9000")),
+            "HELLO_ROOT" => Some(String::from("/srv/www")),
+            _ => None,
+        };
+        let config = Config::build(args(&["--port", "0", "--workers=3", "--dev", "--host", "::1"]), env).unwrap();
+        assert_eq!(config.port, 0);
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.root, PathBuf::from("/srv/www"));
+        assert_eq!(config.address(), "[::1]:0");
+        assert!(config.dev);
+        assert_eq!(config.keep_alive_timeout, Config::default().keep_alive_timeout);
+    }
+
+    #[test]
+    fn proxy_routes_match_whole_segments() {
+        let route: ProxyRoute = "/api=127.0.0.1:9000, 127.0.0.1:9001".parse().unwrap();
+        assert_eq!(route.upstreams, ["127.0.0.1:9000", "127.0.0.1:9001"]);
+        assert!(route.matches("/api") && route.matches("/api/items") && route.matches("/api?page=2"));
+        assert!(!route.matches("/apis") && !route.matches("/"));
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        let no_env = |_: &str| None;
+        assert_eq!(
+            Config::build(args(&["--port", "http"]), no_env),
+            Err(ConfigError::InvalidValue { name: String::from("--port"), value: String::from("http") })
+        );
+        assert_eq!(
+            Config::build(args(&["--workers"]), no_env),
+            Err(ConfigError::MissingValue(String::from("--workers")))
+        );
+        assert_eq!(
+            Config::build(args(&["--verbose"]), no_env),
+            Err(ConfigError::UnknownFlag(String::from("--verbose")))
+        );
+        assert!(matches!(Config::build(args(&["--workers", "0"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::build(args(&["--max-headers", "0"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::build(args(&["--tls-cert", "cert.pem"]), no_env), Err(ConfigError::Requires { .. })));
+        assert!(matches!(Config::build(args(&["--proxy", "api=localhost:80"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::build(args(&["--unix-mode", "800"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert_eq!(Config::build(args(&["--unix-mode", "600"]), no_env).unwrap().unix_mode, 0o600);
+        assert_eq!(Config::build(args(&["--help"]), no_env), Err(ConfigError::HelpRequested));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::http::{Request, Response};
+
+// Something that turns a request into a response. The server doesn't care what's behind it: a
+// static page, a router, a proxy... Handlers are shared by all workers, so `&self` and
+// `Send + Sync`. Any `Fn(&Request) -> Response` closure is a handler:
+//
+// let hello = |_: &Request| Response::text(StatusCode::Ok, "hi");
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+// Wraps a handler to do something for every request, whichever handler ends up answering it:
+// logging, compression, auth... It gets the request first and decides whether (and with what) to
+// call `next`, then gets to change the response on its way out.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response;
+}
+
+// A handler with middlewares around it. The first middleware added is the outermost one: it sees
+// the request first and the response last.
+//
+// let app = Chain::new(site).with(AccessLog::stdout()).with(Gzip::default());
+pub struct Chain {
+    middlewares: Vec<Box<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Chain {
+    pub fn new(handler: impl Handler + 'static) -> Chain {
+        Chain { middlewares: Vec::new(), handler: Box::new(handler) }
+    }
+
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Chain {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, request: &Request) -> Response {
+        Next { middlewares: &self.middlewares, handler: &*self.handler }.handle(request)
+    }
+}
+
+// The rest of the chain, as seen from one middleware.
+struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Handler for Next<'_> {
+    fn handle(&self, request: &Request) -> Response {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => {
+                middleware.handle(request, &Next { middlewares: rest, handler: self.handler })
+            }
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+
+    // Adds its name to the `X-Order` header on the way out.
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+            let mut response = next.handle(request);
+            let order = response.headers.get("X-Order").map(String::from).unwrap_or_default();
+            response.headers.set("X-Order", format!("{order}{}", self.0));
+            response
+        }
+    }
+
+    #[test]
+    fn first_middleware_is_outermost() {
+        let chain = Chain::new(|_: &Request| Response::new(StatusCode::Ok)).with(Tag("outer")).with(Tag("inner,"));
+        let request = Request::read_from(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap().unwrap();
+        assert_eq!(chain.handle(&request).headers.get("X-Order"), Some("inner,outer"));
+    }
+}
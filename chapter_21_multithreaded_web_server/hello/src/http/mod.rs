@@ -0,0 +1,308 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::net::SocketAddr;
+
+use serde::de::DeserializeOwned;
+
+pub mod problem;
+pub mod response;
+
+pub use problem::Problem;
+pub use response::{Body, Headers, OnUpgrade, Response, StatusCode};
+
+// A parsed HTTP/1.x request. We read the whole thing (request line, headers and body) so the
+// reader is left exactly at the start of the next request, that's what makes keep-alive and
+// pipelining work: the client can send several requests back to back and the `BufReader` keeps
+// whatever bytes it already pulled from the socket for the next call.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Who sent it. The parser can't know, the server fills it in.
+    pub remote_addr: Option<SocketAddr>,
+}
+
+// How much a client may send us. Without limits a client could make us buffer a header line
+// that never ends, or allocate whatever `Content-Length` it claims.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // The request line and all header lines together, in bytes.
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { max_header_bytes: 8 * 1024, max_headers: 100, max_body_bytes: 1024 * 1024 }
+    }
+}
+
+// Why we couldn't read a request. Apart from `Io`, every one of these deserves an error
+// response before we close the connection.
+#[derive(Debug)]
+pub enum RequestError {
+    // 400 Bad Request.
+    Malformed(&'static str),
+    // 431 Request Header Fields Too Large, too many header bytes or too many headers.
+    HeadersTooLarge,
+    // 413 Content Too Large.
+    BodyTooLarge,
+    // 408 Request Timeout: the client started a request but didn't finish it in time.
+    TimedOut,
+    // The connection broke, there's nobody left to answer.
+    Io(io::Error),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Malformed(message) => write!(f, "bad request: {message}"),
+            RequestError::HeadersTooLarge => write!(f, "request headers too large"),
+            RequestError::BodyTooLarge => write!(f, "request body too large"),
+            RequestError::TimedOut => write!(f, "timed out reading the request"),
+            RequestError::Io(e) => write!(f, "failed to read the request: {e}"),
+        }
+    }
+}
+
+impl Error for RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RequestError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl RequestError {
+    // What to answer with, `None` if the connection is gone.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RequestError::Malformed(_) => Some(StatusCode::BadRequest),
+            RequestError::HeadersTooLarge => Some(StatusCode::RequestHeaderFieldsTooLarge),
+            RequestError::BodyTooLarge => Some(StatusCode::ContentTooLarge),
+            RequestError::TimedOut => Some(StatusCode::RequestTimeout),
+            RequestError::Io(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> RequestError {
+        match e.kind() {
+            // What a read timeout on a socket looks like, depending on the platform.
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => RequestError::TimedOut,
+            // `read_line` got bytes that aren't UTF-8.
+            io::ErrorKind::InvalidData => RequestError::Malformed("request is not valid UTF-8"),
+            _ => RequestError::Io(e),
+        }
+    }
+}
+
+impl Request {
+    // Returns `Ok(None)` when the client closed the connection before sending anything, which
+    // is the normal way a keep-alive connection ends.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Option<Request>, RequestError> {
+        Request::read_with_limits(reader, &Limits::default())
+    }
+
+    pub fn read_with_limits<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<Request>, RequestError> {
+        let Some(mut request) = Request::read_head(reader, limits)? else {
+            return Ok(None);
+        };
+        let length = request.body_len(limits)?;
+        if length > 0 {
+            request.body = vec![0; length];
+            reader.read_exact(&mut request.body)?;
+        }
+        Ok(Some(request))
+    }
+
+    // Just the request line and headers, `body` is left empty. For when the body has to be read
+    // some other way, like the async server does.
+    pub fn read_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<Request>, RequestError> {
+        let mut line = String::new();
+        let mut header_bytes_left = limits.max_header_bytes;
+
+        // RFC 9112 says servers should ignore at least one empty line before the request line,
+        // some clients send an extra CRLF after a body.
+        loop {
+            line.clear();
+            if read_line(reader, &mut line, &mut header_bytes_left)? == 0 {
+                return Ok(None);
+            }
+            if !line.trim_end().is_empty() {
+                break;
+            }
+        }
+
+        let mut parts = line.trim_end().split(' ');
+        let (method, path, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(path), Some(version), None) if version.starts_with("HTTP/1.") => {
+                (method.to_string(), path.to_string(), version.to_string())
+            }
+            _ => return Err(RequestError::Malformed("malformed request line")),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if read_line(reader, &mut line, &mut header_bytes_left)? == 0 {
+                return Err(RequestError::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if headers.len() == limits.max_headers {
+                return Err(RequestError::HeadersTooLarge);
+            }
+            let (name, value) = header
+                .split_once(':')
+                .ok_or(RequestError::Malformed("malformed header"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        Ok(Some(Request { method, path, version, headers, body: Vec::new(), remote_addr: None }))
+    }
+
+    // How many body bytes follow the head, going by `Content-Length`.
+    pub fn body_len(&self, limits: &Limits) -> Result<usize, RequestError> {
+        // We don't decode chunked request bodies, and guessing where the body ends would
+        // desync every pipelined request after it, so we refuse it instead.
+        if self.header("Transfer-Encoding").is_some() {
+            return Err(RequestError::Malformed("Transfer-Encoding request bodies are not supported"));
+        }
+        let Some(length) = self.header("Content-Length") else {
+            return Ok(0);
+        };
+        let length: usize = length
+            .parse()
+            .map_err(|_| RequestError::Malformed("invalid Content-Length"))?;
+        // Check before allocating, the length is whatever the client says it is.
+        if length > limits.max_body_bytes {
+            return Err(RequestError::BodyTooLarge);
+        }
+        Ok(length)
+    }
+
+    // Header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The body parsed as JSON. The error is ready to send back: 415 if the `Content-Type` says
+    // it isn't JSON, 400 if it doesn't parse, 422 if it parses but doesn't fit `T` (a missing
+    // field, a string where a number should be...).
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Problem> {
+        let content_type = self.header("Content-Type").unwrap_or("");
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        // application/json, or one of its relatives like application/merge-patch+json.
+        if media_type != "application/json" && !media_type.ends_with("+json") {
+            let detail = "the body must be JSON, with Content-Type: application/json";
+            return Err(Problem::new(StatusCode::UnsupportedMediaType, detail));
+        }
+        serde_json::from_slice(&self.body).map_err(|e| {
+            let status = if e.is_data() { StatusCode::UnprocessableContent } else { StatusCode::BadRequest };
+            Problem::new(status, e.to_string())
+        })
+    }
+
+    // HTTP/1.1 connections are persistent unless the client says `Connection: close`,
+    // HTTP/1.0 ones are only persistent if the client asks for `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("");
+        let has = |token: &str| {
+            connection
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        if self.version == "HTTP/1.0" {
+            has("keep-alive")
+        } else {
+            !has("close")
+        }
+    }
+}
+
+// `read_line`, but it gives up once the line would take more than `bytes_left` (and takes what
+// it read off `bytes_left`). A plain `read_line` keeps buffering until it sees a newline, however
+// long that takes.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String, bytes_left: &mut usize) -> Result<usize, RequestError> {
+    let read = reader.take(*bytes_left as u64 + 1).read_line(line)?;
+    if read > *bytes_left {
+        return Err(RequestError::HeadersTooLarge);
+    }
+    *bytes_left -= read;
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    #[test]
+    fn reads_pipelined_requests() {
+        let raw = "\
+POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut reader = BufReader::new(raw.as_bytes());
+
+        let first = Request::read_from(&mut reader).unwrap().unwrap();
+        assert_eq!(first.method, "POST");
+        assert_eq!(first.body, b"hello");
+        assert!(first.keep_alive());
+
+        let second = Request::read_from(&mut reader).unwrap().unwrap();
+        assert_eq!(second.path, "/");
+        assert!(!second.keep_alive());
+
+        assert!(Request::read_from(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn http_1_0_closes_by_default() {
+        let mut reader = BufReader::new("GET / HTTP/1.0\r\n\r\n".as_bytes());
+        let request = Request::read_from(&mut reader).unwrap().unwrap();
+        assert!(!request.keep_alive());
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let limits = Limits { max_header_bytes: 64, max_headers: 2, max_body_bytes: 4 };
+        let read = |raw: String| Request::read_with_limits(&mut BufReader::new(raw.as_bytes()), &limits);
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+        assert!(matches!(read(long_line), Err(RequestError::HeadersTooLarge)));
+        let many_headers = String::from("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n");
+        assert!(matches!(read(many_headers), Err(RequestError::HeadersTooLarge)));
+        let big_body = String::from("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(matches!(read(big_body), Err(RequestError::BodyTooLarge)));
+        let fits = String::from("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nhey!");
+        assert_eq!(read(fits).unwrap().unwrap().body, b"hey!");
+    }
+
+    #[test]
+    fn json_bodies_are_checked() {
+        let request = |content_type: &str, body: &str| {
+            let length = body.len();
+            let raw = format!("POST / HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {length}\r\n\r\n{body}");
+            Request::read_from(&mut BufReader::new(raw.as_bytes())).unwrap().unwrap()
+        };
+        let status = |request: Request| request.json::<Vec<u32>>().unwrap_err().status;
+
+        assert_eq!(request("application/json; charset=utf-8", "[1, 2]").json::<Vec<u32>>().unwrap(), [1, 2]);
+        assert_eq!(status(request("text/plain", "[1, 2]")), StatusCode::UnsupportedMediaType);
+        assert_eq!(status(request("application/json", "[1, 2")), StatusCode::BadRequest);
+        assert_eq!(status(request("application/json", "[\"one\"]")), StatusCode::UnprocessableContent);
+    }
+}
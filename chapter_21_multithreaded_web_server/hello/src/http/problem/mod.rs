@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::{Response, StatusCode};
+
+// An error for API clients, as a "problem details" document (RFC 9457) instead of an HTML page:
+//
+// {"type":"about:blank","title":"Unprocessable Content","status":422,
+//  "detail":"the todo isn't valid","errors":{"title":"can't be empty"}}
+//
+// `errors` says what's wrong with which field, so a form can show each message next to its
+// field. Handlers return it with `?` or `.into()`, it becomes a `Response` like anything else.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    // We don't have pages describing our problem types, "about:blank" says the status is all
+    // there is to know.
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    #[serde(serialize_with = "serialize_status")]
+    pub status: StatusCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<String, String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Problem {
+        Problem {
+            kind: String::from("about:blank"),
+            title: status.reason().to_string(),
+            status,
+            detail: Some(detail.into()),
+            errors: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_error(mut self, field: impl Into<String>, message: impl Into<String>) -> Problem {
+        self.errors.insert(field.into(), message.into());
+        self
+    }
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u16(status.code())
+}
+
+impl From<Problem> for Response {
+    fn from(problem: Problem) -> Response {
+        Response::json(problem.status, &problem).with_header("Content-Type", "application/problem+json")
+    }
+}
@@ -0,0 +1,426 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::server::Upgraded;
+
+// Everything we send back, instead of `format!`ing the status line, headers and body by hand in
+// every handler. Build one, then `write_to` the stream:
+//
+// Response::html(StatusCode::Ok, "<h1>Hi!</h1>").with_header("Cache-Control", "no-cache")
+//
+// `write_to` takes care of the parts that are easy to get wrong: `Content-Length` (or chunked
+// encoding when we don't know the length up front) and leaving out the body where HTTP says
+// there can't be one.
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+    // Set for a `101 Switching Protocols` response: after sending it, the server stops speaking
+    // HTTP on the connection and hands it to this instead, see `websocket::upgrade`.
+    pub upgrade: Option<OnUpgrade>,
+    // Why we answered with a 500 instead of what was asked for. It isn't sent, the client only
+    // gets the status, but `ErrorLog` writes it down for us.
+    pub error: Option<Box<dyn Error + Send + Sync>>,
+}
+
+pub type OnUpgrade = Box<dyn FnOnce(Upgraded) + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    NoContent,
+    MovedPermanently,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    ContentTooLarge,
+    UnsupportedMediaType,
+    UnprocessableContent,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    // Any code we don't have a name for, e.g. one an upstream server sent, see `proxy`.
+    Other(u16),
+}
+
+impl StatusCode {
+    pub fn code(self) -> u16 {
+        match self {
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::NoContent => 204,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::NotModified => 304,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::ContentTooLarge => 413,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::UnprocessableContent => 422,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::GatewayTimeout => 504,
+            StatusCode::Other(code) => code,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::NoContent => "No Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::ContentTooLarge => "Content Too Large",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::UnprocessableContent => "Unprocessable Content",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            // The reason phrase is optional, clients go by the code.
+            StatusCode::Other(_) => "",
+        }
+    }
+
+    // The named variant for `code` if there is one.
+    pub fn from_code(code: u16) -> StatusCode {
+        const NAMED: [StatusCode; 22] = [
+            StatusCode::SwitchingProtocols,
+            StatusCode::Ok,
+            StatusCode::Created,
+            StatusCode::NoContent,
+            StatusCode::MovedPermanently,
+            StatusCode::NotModified,
+            StatusCode::BadRequest,
+            StatusCode::Unauthorized,
+            StatusCode::Forbidden,
+            StatusCode::NotFound,
+            StatusCode::MethodNotAllowed,
+            StatusCode::RequestTimeout,
+            StatusCode::ContentTooLarge,
+            StatusCode::UnsupportedMediaType,
+            StatusCode::UnprocessableContent,
+            StatusCode::UpgradeRequired,
+            StatusCode::TooManyRequests,
+            StatusCode::RequestHeaderFieldsTooLarge,
+            StatusCode::InternalServerError,
+            StatusCode::BadGateway,
+            StatusCode::ServiceUnavailable,
+            StatusCode::GatewayTimeout,
+        ];
+        NAMED.into_iter().find(|status| status.code() == code).unwrap_or(StatusCode::Other(code))
+    }
+
+    // 1xx, 204 and 304 responses never have a body, not even an empty one with a length.
+    pub fn allows_body(self) -> bool {
+        !matches!(self.code(), 100..=199 | 204 | 304)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+// Response headers in the order they were added. Names are compared case-insensitively, like
+// `Request::header` does.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Replaces any header with the same name.
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.0.push((name, value.into()));
+    }
+
+    // Adds another header even if there's one with that name already, e.g. for `Set-Cookie`.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // Streamed from the file instead of read into memory first. We need the length up front for
+    // `Content-Length`.
+    File { file: File, len: u64 },
+    // Anything else we can read `len` bytes from as we send them, like an upstream server's
+    // response.
+    Stream { reader: Box<dyn Read + Send>, len: u64 },
+    // For bodies we produce as we go and don't know the length of. Sent with
+    // `Transfer-Encoding: chunked`, one chunk per item.
+    Chunked(Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>),
+}
+
+impl Body {
+    // `None` for a chunked body.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } | Body::Stream { len, .. } => Some(*len),
+            Body::Chunked(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Empty => write!(f, "Empty"),
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::File { len, .. } => write!(f, "File({len} bytes)"),
+            Body::Stream { len, .. } => write!(f, "Stream({len} bytes)"),
+            Body::Chunked(_) => write!(f, "Chunked"),
+        }
+    }
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response { status, headers: Headers::default(), body: Body::Empty, upgrade: None, error: None }
+    }
+
+    pub fn html(status: StatusCode, html: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(Body::Bytes(html.into().into_bytes()))
+    }
+
+    // `value` as JSON. Serializing only fails for types JSON can't express (like a map with
+    // non-string keys), that's a bug on our side, so it becomes a 500 that carries the error.
+    pub fn json(status: StatusCode, value: &impl Serialize) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(json) => Response::new(status)
+                .with_header("Content-Type", "application/json")
+                .with_body(Body::Bytes(json)),
+            Err(e) => Response::text(StatusCode::InternalServerError, "Internal Server Error").with_error(e),
+        }
+    }
+
+    pub fn text(status: StatusCode, text: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(Body::Bytes(text.into().into_bytes()))
+    }
+
+    // The file's contents with a `Content-Type` guessed from its extension.
+    pub fn file(status: StatusCode, path: &Path) -> io::Result<Response> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Response::new(status)
+            .with_header("Content-Type", content_type(path))
+            .with_body(Body::File { file, len }))
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.set(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: Body) -> Response {
+        self.body = body;
+        self
+    }
+
+    pub fn with_error(mut self, error: impl Into<Box<dyn Error + Send + Sync>>) -> Response {
+        self.error = Some(error.into());
+        self
+    }
+
+    pub fn with_upgrade(mut self, upgrade: impl FnOnce(Upgraded) + Send + 'static) -> Response {
+        self.upgrade = Some(Box::new(upgrade));
+        self
+    }
+
+    // Serializes the whole response, head first (see `into_head`), then the body.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        let (head, body) = self.into_head();
+        // Small writes straight to a socket mean a syscall (and maybe a packet) each.
+        let mut writer = io::BufWriter::new(writer);
+        writer.write_all(&head)?;
+
+        match body {
+            Body::Empty => {}
+            Body::Bytes(bytes) => writer.write_all(&bytes)?,
+            Body::File { file, len } => {
+                // If the file shrank since we looked at its length, the client would wait for
+                // bytes that never come, so that's an error.
+                let copied = io::copy(&mut file.take(len), &mut writer)?;
+                if copied < len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+            }
+            Body::Stream { reader, len } => {
+                if io::copy(&mut reader.take(len), &mut writer)? < len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+            }
+            Body::Chunked(chunks) => {
+                for chunk in chunks {
+                    let chunk = chunk?;
+                    // An empty chunk would mark the end of the body.
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    writer.write_all(&chunk_header(chunk.len()))?;
+                    writer.write_all(&chunk)?;
+                    writer.write_all(b"\r\n")?;
+                    // The point of streaming is that the client gets each chunk as it's ready.
+                    writer.flush()?;
+                }
+                writer.write_all(LAST_CHUNK)?;
+            }
+        }
+        writer.flush()
+    }
+
+    // The answer to a HEAD request: the head a GET would get, `Content-Length` and all, but no
+    // body. The client stops reading after the head, anything more would be taken for the start
+    // of the next response.
+    pub fn write_head_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        let (head, _) = self.into_head();
+        writer.write_all(&head)?;
+        writer.flush()
+    }
+
+    // The status line and headers, ready to send, and the body that goes after them. Anything in
+    // `headers` that describes the body's framing (`Content-Length`, `Transfer-Encoding`) is
+    // replaced by what the body actually is. The body is `Empty` where HTTP says there can't be
+    // one.
+    pub fn into_head(self) -> (Vec<u8>, Body) {
+        let Response { status, mut headers, body, .. } = self;
+        headers.remove("Content-Length");
+        headers.remove("Transfer-Encoding");
+        let body = if status.allows_body() { body } else { Body::Empty };
+        if status.allows_body() {
+            match body.len() {
+                Some(len) => headers.set("Content-Length", len.to_string()),
+                None => headers.set("Transfer-Encoding", "chunked"),
+            }
+        }
+
+        let mut head = format!("HTTP/1.1 {status}\r\n");
+        for (name, value) in headers.iter() {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        (head.into_bytes(), body)
+    }
+}
+
+// What goes before each chunk of a chunked body, its length in hex. An empty chunk would mark
+// the end of the body, that's `LAST_CHUNK`.
+pub fn chunk_header(len: usize) -> Vec<u8> {
+    format!("{len:X}\r\n").into_bytes()
+}
+
+pub const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn serializes_headers_and_length() {
+        let response = Response::text(StatusCode::NotFound, "nope")
+            .with_header("X-Test", "1")
+            .with_header("content-length", "999");
+        assert_eq!(
+            serialize(response),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\nX-Test: 1\r\nContent-Length: 4\r\n\r\nnope"
+        );
+        assert_eq!(
+            serialize(Response::text(StatusCode::NoContent, "dropped")),
+            "HTTP/1.1 204 No Content\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn streams_chunked_bodies() {
+        let chunks = vec![Ok(b"hello ".to_vec()), Ok(Vec::new()), Ok(b"world".to_vec())];
+        let response = Response::new(StatusCode::Ok).with_body(Body::Chunked(Box::new(chunks.into_iter())));
+        assert_eq!(
+            serialize(response),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"
+        );
+    }
+}
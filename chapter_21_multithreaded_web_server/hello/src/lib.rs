@@ -1,10 +1,413 @@
-use std::sync::{ mpsc, Arc, Mutex };
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod client;
+pub mod config;
+pub mod handler;
+pub mod http;
+pub mod middleware;
+pub mod proxy;
+pub mod server;
+pub mod task;
+pub mod scope;
+pub mod schedule;
+pub mod stats;
+pub mod template;
+pub mod todos;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod websocket;
+mod queue;
+
+use std::fmt;
+use std::error::Error;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{ mpsc, Arc, Mutex, PoisonError };
+
+use queue::{JobQueue, Next, Queued};
+use schedule::Timer;
+use stats::LatencyHistogram;
 use std::thread::JoinHandle;
 use std::thread;
+use std::time::{Duration, Instant};
+
+pub use schedule::ScheduleHandle;
+pub use scope::Scope;
+pub use stats::{Histogram, PoolEvent, PoolObserver, PoolStats};
+pub use task::{TaskError, TaskHandle};
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    rejection_policy: RejectionPolicy,
+    shared: Arc<Shared>,
+    supervisor: Option<JoinHandle<()>>,
+    // Holds the jobs from `execute_after` and `execute_every` until they're due.
+    timer: Arc<Timer>,
+    timer_thread: Option<JoinHandle<()>>,
+}
+
+// Everything the workers and the supervisor need to get at.
+struct Shared {
+    queue: JobQueue,
+    // Every worker thread that is running (or just finished and wasn't joined yet). Shared
+    // because the supervisor swaps dead workers for new ones and idle workers remove themselves.
+    workers: Mutex<Vec<Worker>>,
+    // How many workers we have. Kept next to `workers` so `execute` can check it without the lock.
+    live_workers: AtomicUsize,
+    min_threads: usize,
+    max_threads: usize,
+    keep_alive: Duration,
+    events: mpsc::Sender<SupervisorEvent>,
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+    observer: Option<Arc<dyn PoolObserver>>,
+    // Workers that are running a job right now.
+    active_workers: AtomicUsize,
+    completed_jobs: AtomicU64,
+    panicked_jobs: AtomicUsize,
+    respawned_workers: AtomicUsize,
+    rejected_jobs: AtomicUsize,
+    queue_wait: LatencyHistogram,
+    run_time: LatencyHistogram,
+}
+
+impl Shared {
+    fn notify(&self, event: PoolEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    // `queued_at` is when the job was submitted, `started` when a thread started running it.
+    fn job_finished(&self, queued_at: Instant, started: Instant) {
+        let waited = started - queued_at;
+        let ran = started.elapsed();
+        self.queue_wait.record(waited);
+        self.run_time.record(ran);
+        self.completed_jobs.fetch_add(1, Ordering::Relaxed);
+        self.notify(PoolEvent::JobFinished { waited, ran });
+    }
+
+    fn job_panicked(&self) {
+        self.panicked_jobs.fetch_add(1, Ordering::Relaxed);
+        self.notify(PoolEvent::JobPanicked);
+    }
+
+    fn job_rejected(&self) {
+        self.rejected_jobs.fetch_add(1, Ordering::Relaxed);
+        self.notify(PoolEvent::JobRejected);
+    }
+
+    fn thread_builder(&self, name: &str) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &self.thread_name {
+            builder = builder.name(format!("{prefix}-{name}"));
+        }
+        if let Some(bytes) = self.stack_size {
+            builder = builder.stack_size(bytes);
+        }
+        builder
+    }
+
+    // A new job is coming in: start another worker if nobody is free to take it and we're
+    // below `max_threads`.
+    fn grow_if_backed_up(self: &Arc<Shared>) {
+        if self.queue.len() < self.queue.available_workers() {
+            return;
+        }
+        let reserved = self
+            .live_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live < self.max_threads).then_some(live + 1)
+            })
+            .is_ok();
+        if !reserved {
+            return;
+        }
+
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        // Reuse the lowest free id, so ids (and thread names) stay small.
+        let id = (0..).find(|id| workers.iter().all(|worker| worker.id != *id)).unwrap();
+        match Worker::new(id, Arc::clone(self)) {
+            Ok(worker) => workers.push(worker),
+            // We still have the workers we had, the job just waits a bit longer.
+            Err(error) => {
+                self.live_workers.fetch_sub(1, Ordering::SeqCst);
+                self.notify(PoolEvent::SpawnFailed { id, error });
+            }
+        }
+    }
+
+    // Called by a worker that had nothing to do for `keep_alive`. Returns `true` if it should
+    // exit, which it only may while we have more than `min_threads`.
+    fn retire(&self, id: usize) -> bool {
+        let retired = self
+            .live_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > self.min_threads).then_some(live - 1)
+            })
+            .is_ok();
+        if retired {
+            // The thread is about to end, so dropping its `JoinHandle` just detaches it. If the
+            // pool is being dropped right now the handle isn't in the list anymore and the pool
+            // joins it instead.
+            let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(index) = workers.iter().position(|worker| worker.id == id) {
+                workers.swap_remove(index);
+            }
+        }
+        retired
+    }
+
+    // Takes the workers out of the list, so we don't hold the lock while joining them: a worker
+    // that retires at the same time needs it.
+    fn take_workers(&self) -> Vec<Worker> {
+        mem::take(&mut *self.workers.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+enum SupervisorEvent {
+    WorkerDied(usize),
+    Stop,
+}
+
+// What `execute` does when a bounded queue is full. Without a queue capacity the queue is never
+// full and this doesn't matter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionPolicy {
+    // Wait until a worker makes room. This is the default.
+    Block,
+    // Give up and return `ExecuteError::QueueFull`.
+    Reject,
+    // Throw away the job that has been waiting the longest to make room for the new one.
+    DropOldest,
+    // Run the job right away on the thread that called `execute`. That slows the caller down,
+    // which is exactly the kind of backpressure a server's accept loop needs.
+    CallerRuns,
+}
+
+// Which jobs a worker picks up first. A high priority job jumps ahead of every normal job that
+// is waiting, e.g. a health check shouldn't wait behind a pile of requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecuteError {
+    QueueFull,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => write!(f, "the thread pool's queue is full"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+#[derive(Debug)]
+pub enum PoolCreationError {
+    ZeroSize,
+    // `min_threads` is bigger than `max_threads`.
+    InvalidBounds { min: usize, max: usize },
+    // The OS refused to give us another thread, e.g. we hit the process' thread limit.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => write!(f, "a thread pool needs at least one thread"),
+            PoolCreationError::InvalidBounds { min, max } => {
+                write!(f, "a thread pool can't keep {min} threads alive with at most {max}")
+            }
+            PoolCreationError::Spawn(e) => write!(f, "failed to spawn a worker thread: {e}"),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize | PoolCreationError::InvalidBounds { .. } => None,
+            PoolCreationError::Spawn(e) => Some(e),
+        }
+    }
+}
+
+// Everything optional about a pool. Start one with `ThreadPool::builder()`, chain the settings
+// you care about and finish with `build`:
+// ThreadPool::builder().size(8).thread_name("worker").stack_size(64 * 1024).build()?
+pub struct ThreadPoolBuilder {
+    min_threads: usize,
+    max_threads: usize,
+    keep_alive: Duration,
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+    queue_capacity: Option<usize>,
+    rejection_policy: RejectionPolicy,
+    observer: Option<Arc<dyn PoolObserver>>,
+}
+
+impl ThreadPoolBuilder {
+    // A fixed number of threads, same as `min_threads(size).max_threads(size)`.
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.min_threads = size;
+        self.max_threads = size;
+        self
+    }
+
+    // The pool starts with `min` threads and never goes below that.
+    pub fn min_threads(mut self, min: usize) -> ThreadPoolBuilder {
+        self.min_threads = min;
+        self
+    }
+
+    // When a job comes in and every thread is busy, the pool starts another one, up to `max`.
+    pub fn max_threads(mut self, max: usize) -> ThreadPoolBuilder {
+        self.max_threads = max;
+        self
+    }
+
+    // How long a thread above `min_threads` may sit idle before it exits. Defaults to 60s.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    // Workers are called "{prefix}-{id}", which shows up in panic messages and debuggers.
+    pub fn thread_name(mut self, prefix: impl Into<String>) -> ThreadPoolBuilder {
+        self.thread_name = Some(prefix.into());
+        self
+    }
+
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    // At most `capacity` jobs wait in the queue, what happens to the next one is up to the
+    // `rejection_policy`. A capacity of 0 means a job is only accepted if a worker is idle.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    pub fn rejection_policy(mut self, policy: RejectionPolicy) -> ThreadPoolBuilder {
+        self.rejection_policy = policy;
+        self
+    }
+
+    // Gets told about workers coming and going and about every job that ran, see `PoolEvent`.
+    pub fn observer(mut self, observer: impl PoolObserver + 'static) -> ThreadPoolBuilder {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.max_threads == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if self.min_threads > self.max_threads {
+            return Err(PoolCreationError::InvalidBounds { min: self.min_threads, max: self.max_threads });
+        }
+
+        let (events, supervisor_events) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            queue: JobQueue::new(self.queue_capacity),
+            workers: Mutex::new(Vec::with_capacity(self.max_threads)),
+            live_workers: AtomicUsize::new(self.min_threads),
+            min_threads: self.min_threads,
+            max_threads: self.max_threads,
+            keep_alive: self.keep_alive,
+            events,
+            thread_name: self.thread_name,
+            stack_size: self.stack_size,
+            observer: self.observer,
+            active_workers: AtomicUsize::new(0),
+            completed_jobs: AtomicU64::new(0),
+            panicked_jobs: AtomicUsize::new(0),
+            respawned_workers: AtomicUsize::new(0),
+            rejected_jobs: AtomicUsize::new(0),
+            queue_wait: LatencyHistogram::new(),
+            run_time: LatencyHistogram::new(),
+        });
+
+        let mut workers = shared.workers.lock().unwrap_or_else(PoisonError::into_inner);
+
+        for id in 0..self.min_threads {
+            // let worker = Worker::new(id, receiver); // This will not work as `receiver` or consumer, is mpsc (single consumer)
+            // And we move it in first iteration
+            // So now the workers can share the ownership of the receiver using `Arc<Mutex<>>`
+            // (These days the workers share a `JobQueue` instead of a receiver, see `queue`.)
+            match Worker::new(id, Arc::clone(&shared)) {
+                Ok(worker) => workers.push(worker),
+                // Close the queue so the workers we already started exit on their own.
+                Err(e) => {
+                    shared.queue.close();
+                    return Err(PoolCreationError::Spawn(e));
+                }
+            }
+        }
+        drop(workers);
+
+        let supervisor = {
+            let shared_for_supervisor = Arc::clone(&shared);
+            shared
+                .thread_builder("supervisor")
+                .spawn(move || supervise(supervisor_events, shared_for_supervisor))
+                .map_err(|e| {
+                    shared.queue.close();
+                    PoolCreationError::Spawn(e)
+                })?
+        };
+
+        let timer = Arc::new(Timer::new());
+        let timer_thread = {
+            let timer = Arc::clone(&timer);
+            let shared_for_timer = Arc::clone(&shared);
+            shared.thread_builder("timer").spawn(move || timer.run(&shared_for_timer))
+        };
+        let mut pool = ThreadPool {
+            rejection_policy: self.rejection_policy,
+            shared,
+            supervisor: Some(supervisor),
+            timer,
+            timer_thread: None,
+        };
+        // Dropping the pool stops the workers and the supervisor we already started.
+        pool.timer_thread = Some(timer_thread.map_err(PoolCreationError::Spawn)?);
+        Ok(pool)
+    }
+}
+
+// The supervisor sleeps until a worker thread dies and then puts a fresh worker with the same id
+// in its place, so the pool never silently shrinks. It stops when the pool is shutting down.
+fn supervise(events: mpsc::Receiver<SupervisorEvent>, shared: Arc<Shared>) {
+    while let Ok(SupervisorEvent::WorkerDied(id)) = events.recv() {
+        let mut workers = shared.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(slot) = workers.iter_mut().find(|worker| worker.id == id) else {
+            continue;
+        };
+        match Worker::new(id, Arc::clone(&shared)) {
+            Ok(worker) => {
+                let dead = mem::replace(slot, worker);
+                // The thread panicked, so `join` returns that panic. The panic hook already
+                // printed it, we only wait for the thread to be fully gone.
+                let _ = dead.thread.join();
+                shared.respawned_workers.fetch_add(1, Ordering::Relaxed);
+                shared.notify(PoolEvent::WorkerRespawned { id });
+            }
+            // We keep the dead worker's handle around and try again next time one dies.
+            Err(error) => shared.notify(PoolEvent::SpawnFailed { id, error }),
+        }
+    }
 }
 
 struct Worker {
@@ -13,19 +416,75 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
+    // `thread::Builder::spawn` returns an error instead of panicking like `thread::spawn` does.
+    fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let builder = shared.thread_builder(&id.to_string());
+        let deque = shared.queue.register(id);
+        let thread = builder.spawn(move || {
+            let _sentinel = Sentinel { id, shared: &shared };
+            shared.queue.attach(deque);
+            shared.notify(PoolEvent::WorkerStarted { id });
+
+            // NOTE: We used to print "Worker {id} got a job; executing." here, but printing
+            // locks stdout, so every job went through one lock again. That is exactly the
+            // contention the work-stealing queue is there to avoid. Whoever wants to know can
+            // set an observer now.
+            // A fixed-size pool has no reason to ever time out.
+            let idle_timeout = (shared.min_threads < shared.max_threads).then_some(shared.keep_alive);
             loop {
-                let job = receiver.lock().unwrap().recv().unwrap();
-                println!("Worker {id} got a job; executing.");
-                job();
+                match shared.queue.next_job(idle_timeout) {
+                    Next::Job(Queued { job, queued_at }) => {
+                        let started = Instant::now();
+                        shared.active_workers.fetch_add(1, Ordering::Relaxed);
+                        // A panicking job must not kill the worker. `catch_unwind` stops the
+                        // unwinding here, the panic hook has already printed the message.
+                        // `AssertUnwindSafe` is fine because nothing the job touched is used
+                        // after it panicked.
+                        let result = panic::catch_unwind(AssertUnwindSafe(job));
+                        shared.active_workers.fetch_sub(1, Ordering::Relaxed);
+                        shared.job_finished(queued_at, started);
+                        if let Err(payload) = result {
+                            shared.job_panicked();
+                            // Dropping the payload runs arbitrary code too, if that panics the
+                            // worker dies and the `Sentinel` calls the supervisor.
+                            drop(payload);
+                        }
+                    }
+                    Next::Idle => {
+                        if shared.retire(id) {
+                            shared.notify(PoolEvent::WorkerRetired { id, idle: shared.keep_alive });
+                            break;
+                        }
+                    }
+                    // The pool is shutting down and the queue is empty.
+                    Next::Closed => {
+                        shared.notify(PoolEvent::WorkerStopped { id });
+                        break;
+                    }
+                }
             }
-        });
+        })?;
 
-        Worker { id, thread }
+        Ok(Worker { id, thread })
     }
 }
-type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Lives on the worker thread's stack. If the thread unwinds, its `drop` still runs and tells the
+// supervisor to replace the worker.
+struct Sentinel<'a> {
+    id: usize,
+    shared: &'a Shared,
+}
+
+impl Drop for Sentinel<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.shared.events.send(SupervisorEvent::WorkerDied(self.id));
+        }
+    }
+}
+
+pub(crate) type Job = Box<dyn FnOnce() + Send + 'static>;
 
 // 1. Define a `Worker` struct that holds an `id` and a `JoinHandle<()>` DONE
 // 2. Change `ThreadPool` to hold a vector of `Worker` instances. DONE
@@ -42,42 +501,600 @@ type Job = Box<dyn FnOnce() + Send + 'static>;
 // 5. In its thread, the `Worker` will loop over its receiver and execute the closures of any jobs it receives.
 
 impl ThreadPool {
+    // Panics if `size` is 0 or a thread can't be spawned, use `build` to handle that instead.
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        ThreadPool::build(size).unwrap_or_else(|e| panic!("{e}"))
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder().size(size).build()
+    }
 
-        for id in 0..size {
-            // let worker = Worker::new(id, receiver); // This will not work as `receiver` or consumer, is mpsc (single consumer)
-            // And we move it in first iteration
-            // So now the workers can share the ownership of the receiver using `Arc<Mutex<>>`
-            let worker = Worker::new(id, Arc::clone(&receiver));
-            workers.push(worker);
+    // Defaults to a fixed one worker per CPU, unnamed threads, the default stack size and an
+    // unbounded queue.
+    pub fn builder() -> ThreadPoolBuilder {
+        let cpus = thread::available_parallelism().map_or(4, |n| n.get());
+        ThreadPoolBuilder {
+            min_threads: cpus,
+            max_threads: cpus,
+            keep_alive: Duration::from_secs(60),
+            thread_name: None,
+            stack_size: None,
+            queue_capacity: None,
+            rejection_policy: RejectionPolicy::Block,
+            observer: None,
         }
-        ThreadPool { workers, sender }
     }
 
-    // pub fn spawn<F, T>(f: F) -> JoinHandle<T>
-    //     where F: FnOnce() -> T, F: Send + 'static, T: Send + 'static {}
+    // Like `execute`, but the closure can return a value. The returned handle works like the
+    // `JoinHandle` from `thread::spawn`: a panic in `f` comes back as `TaskError::Panicked`
+    // instead of taking anything down, and a job the queue turned away comes back as
+    // `TaskError::Rejected`.
+    pub fn spawn<F, T>(&self, f: F) -> TaskHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (handle, completer) = task::task();
+        let shared = Arc::clone(&self.shared);
+        let submitted = self.execute(move || {
+            // We catch the panic ourselves to hand the payload to the handle, so we also have to
+            // count it ourselves.
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+                shared.job_panicked();
+                TaskError::Panicked(payload)
+            });
+            completer.complete(result);
+        });
+        if let Err(e) = submitted {
+            handle.reject(e);
+        }
+        handle
+    }
 
     // Let's finally implement the `execute` method on `ThreadPool`
-    pub fn execute<F>(&self, f: F) where F: FnOnce() + Send + 'static {
-        let job = Box::new(f);
+    // It only fails when the queue is bounded, full and the policy is `RejectionPolicy::Reject`.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError> where F: FnOnce() + Send + 'static {
+        self.execute_job(Box::new(f), Priority::Normal)
+    }
+
+    // Like `execute`, but a `Priority::High` job runs before any normal job that is still
+    // waiting. Jobs with the same priority still start in (roughly) the order they came in.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_job(Box::new(f), priority)
+    }
+
+    // Runs `f` on a worker once `delay` has passed. If the queue is full by then the job is
+    // dropped and counted in `rejected_jobs`, whatever the rejection policy says: the timer can't
+    // block or run jobs itself without delaying every other scheduled job.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> ScheduleHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.timer.once(delay, Box::new(f))
+    }
+
+    // Runs `f` on a worker every `period`, starting one period from now, until the handle is
+    // cancelled or the pool is dropped. If a run is still going when the next one is due, the
+    // next one is skipped, so `f` never runs twice at the same time.
+    pub fn execute_every<F>(&self, period: Duration, f: F) -> ScheduleHandle
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.timer.every(period, f)
+    }
+
+    fn execute_job(&self, job: Job, priority: Priority) -> Result<(), ExecuteError> {
+        let queue = &self.shared.queue;
+        self.shared.grow_if_backed_up();
+
+        if self.rejection_policy == RejectionPolicy::Block {
+            queue.push_blocking(job, priority);
+            return Ok(());
+        }
+
+        let mut job = job;
+        loop {
+            job = match queue.try_push(job, priority) {
+                Ok(()) => return Ok(()),
+                Err(job) => job,
+            };
+
+            match self.rejection_policy {
+                RejectionPolicy::Block => unreachable!(),
+                RejectionPolicy::Reject => {
+                    self.shared.job_rejected();
+                    return Err(ExecuteError::QueueFull);
+                }
+                RejectionPolicy::CallerRuns => {
+                    // Same panic isolation as in the workers, the caller is usually the accept
+                    // loop and must survive a broken job.
+                    let started = Instant::now();
+                    let result = panic::catch_unwind(AssertUnwindSafe(job));
+                    self.shared.job_finished(started, started);
+                    if result.is_err() {
+                        self.shared.job_panicked();
+                    }
+                    return Ok(());
+                }
+                RejectionPolicy::DropOldest => match queue.pop_oldest() {
+                    // Throw it away and try again.
+                    Some(oldest) => {
+                        self.shared.job_rejected();
+                        drop(oldest);
+                    }
+                    // With a capacity of 0 there's nothing waiting we could drop, so the
+                    // best we can do is wait for a worker.
+                    None => {
+                        queue.push_blocking(job, priority);
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
 
-        self.sender.send(job).unwrap();
+    // How many jobs panicked since the pool was created.
+    pub fn panicked_jobs(&self) -> usize {
+        self.shared.panicked_jobs.load(Ordering::Relaxed)
+    }
+
+    // How many jobs were turned away or thrown out because the queue was full.
+    pub fn rejected_jobs(&self) -> usize {
+        self.shared.rejected_jobs.load(Ordering::Relaxed)
+    }
+
+    // How many worker threads the pool has right now, between `min_threads` and `max_threads`.
+    pub fn threads(&self) -> usize {
+        self.shared.live_workers.load(Ordering::SeqCst)
+    }
+
+    // How many workers died and were replaced by the supervisor.
+    pub fn respawned_workers(&self) -> usize {
+        self.shared.respawned_workers.load(Ordering::Relaxed)
+    }
+
+    // Everything the pool counts, in one go. See `PoolStats`.
+    pub fn stats(&self) -> PoolStats {
+        let shared = &self.shared;
+        PoolStats {
+            threads: shared.live_workers.load(Ordering::SeqCst),
+            active_workers: shared.active_workers.load(Ordering::Relaxed),
+            queued_jobs: shared.queue.len(),
+            completed_jobs: shared.completed_jobs.load(Ordering::Relaxed),
+            panicked_jobs: shared.panicked_jobs.load(Ordering::Relaxed),
+            rejected_jobs: shared.rejected_jobs.load(Ordering::Relaxed),
+            respawned_workers: shared.respawned_workers.load(Ordering::Relaxed),
+            queue_wait: shared.queue_wait.snapshot(),
+            run_time: shared.run_time.snapshot(),
+        }
+    }
+
+    // No respawning while we shut down: a worker that dies now just stays dead.
+    fn stop_supervisor(&mut self) {
+        if let Some(supervisor) = self.supervisor.take() {
+            let _ = self.shared.events.send(SupervisorEvent::Stop);
+            let _ = supervisor.join();
+        }
+    }
+
+    // Scheduled jobs that aren't due yet never run.
+    fn stop_timer(&mut self) {
+        self.timer.stop();
+        if let Some(timer_thread) = self.timer_thread.take() {
+            let _ = timer_thread.join();
+        }
+    }
+
+    // Like dropping the pool, but instead of waiting forever for the workers we give them until
+    // `timeout` to finish the jobs they're running (and the ones still queued). Workers that are
+    // still busy after that are detached. Returns `true` if every worker finished in time.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> bool {
+        self.stop_timer();
+        self.stop_supervisor();
+        self.shared.queue.close();
+
+        let mut workers = self.shared.take_workers();
+        let deadline = Instant::now() + timeout;
+        while workers.iter().any(|worker| !worker.thread.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (finished, stuck): (Vec<Worker>, Vec<Worker>) = workers
+            .drain(..)
+            .partition(|worker| worker.thread.is_finished());
+        for worker in finished {
+            // A worker that died after the supervisor stopped has nothing left to report.
+            let _ = worker.thread.join();
+        }
+        for worker in &stuck {
+            self.shared.notify(PoolEvent::WorkerDetached { id: worker.id });
+        }
+        stuck.is_empty()
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        self.stop_timer();
+        self.stop_supervisor();
+        self.shared.queue.close();
+
+        for worker in self.shared.take_workers() {
+            let _ = worker.thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_rejects_zero_threads() {
+        assert!(matches!(ThreadPool::build(0), Err(PoolCreationError::ZeroSize)));
+    }
+
+    #[test]
+    fn builder_names_threads() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .thread_name("test-worker")
+            .stack_size(256 * 1024)
+            .queue_capacity(1)
+            .build()
+            .unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || {
+            let name = thread::current().name().map(String::from);
+            sender.send(name).unwrap();
+        })
+        .unwrap();
+        assert_eq!(receiver.recv().unwrap().as_deref(), Some("test-worker-0"));
+    }
+
+    #[test]
+    fn panicking_job_does_not_take_down_the_pool() {
+        // One worker, so the panicking job is done before the others start.
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("job panicked on purpose")).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..4 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+        let mut results: Vec<i32> = receiver.iter().take(4).collect();
+        results.sort();
+        assert_eq!(results, [0, 1, 2, 3]);
+        assert_eq!(pool.panicked_jobs(), 1);
+        assert_eq!(pool.respawned_workers(), 0);
+    }
+
+    #[test]
+    fn dead_worker_is_respawned() {
+        // A panic payload that panics again when dropped escapes `catch_unwind` and kills the
+        // worker thread.
+        struct Bomb;
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("payload exploded on drop");
+            }
+        }
+
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic::panic_any(Bomb)).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap()).unwrap();
+        // The job can only run on the replacement worker, which may pick it up before the
+        // supervisor bumps the counter.
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        let started = Instant::now();
+        while pool.respawned_workers() == 0 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.panicked_jobs(), 1);
+        assert_eq!(pool.respawned_workers(), 1);
+    }
+
+    // Builds a one-worker pool with room for one queued job and keeps the worker busy until the
+    // returned sender is dropped.
+    fn busy_pool(policy: RejectionPolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .rejection_policy(policy)
+            .build()
+            .unwrap();
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = wait_release.recv();
+        })
+        .unwrap();
+        wait_started.recv().unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn full_queue_rejects_jobs() {
+        let (pool, release) = busy_pool(RejectionPolicy::Reject);
+        pool.execute(|| {}).unwrap();
+        assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+        assert_eq!(pool.rejected_jobs(), 1);
+        drop(release);
+    }
+
+    #[test]
+    fn full_queue_drops_oldest_job() {
+        let (pool, release) = busy_pool(RejectionPolicy::DropOldest);
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..3 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+        drop(sender);
+        drop(release);
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), [2]);
+        assert_eq!(pool.rejected_jobs(), 2);
+    }
+
+    #[test]
+    fn full_queue_runs_job_on_caller() {
+        let (pool, release) = busy_pool(RejectionPolicy::CallerRuns);
+        pool.execute(|| {}).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().id()).unwrap()).unwrap();
+        assert_eq!(receiver.recv().unwrap(), thread::current().id());
+        drop(release);
+    }
+
+    #[test]
+    fn spawn_returns_value_or_panic() {
+        let pool = ThreadPool::new(2);
+        let answer = pool.spawn(|| 6 * 7);
+        let broken = pool.spawn(|| -> i32 { panic!("no answer") });
+
+        assert_eq!(answer.join().unwrap(), 42);
+        assert_eq!(broken.join().unwrap_err().to_string(), "task panicked: no answer");
+        assert_eq!(pool.panicked_jobs(), 1);
+    }
+
+    #[test]
+    fn spawn_handle_is_a_future() {
+        use std::future::Future;
+        use std::pin::pin;
+        use std::task::{Context, Poll, Wake, Waker};
+
+        // The smallest executor there is: park the thread until the waker unparks it.
+        struct ThreadWaker(thread::Thread);
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let pool = ThreadPool::new(1);
+        let (release, wait_release) = mpsc::channel::<()>();
+        let handle = pool.spawn(move || {
+            let _ = wait_release.recv();
+            "done"
+        });
+        assert!(!handle.wait_timeout(Duration::from_millis(10)));
 
-        for worker in &mut self.workers.drain(..) {
-            println!("Shutting down worker {}", worker.id);
-            worker.thread.join().unwrap();
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        let mut handle = pin!(handle);
+        assert!(handle.as_mut().poll(&mut context).is_pending());
+        drop(release);
+        loop {
+            match handle.as_mut().poll(&mut context) {
+                Poll::Ready(result) => break assert_eq!(result.unwrap(), "done"),
+                Poll::Pending => thread::park(),
+            }
         }
     }
+
+    #[test]
+    fn spawn_on_full_queue_is_rejected() {
+        let (pool, release) = busy_pool(RejectionPolicy::Reject);
+        let queued = pool.spawn(|| 1);
+        let rejected = pool.spawn(|| 2);
+        assert!(matches!(
+            rejected.join(),
+            Err(TaskError::Rejected(ExecuteError::QueueFull))
+        ));
+        drop(release);
+        assert_eq!(queued.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn jobs_can_submit_jobs() {
+        // Jobs submitted from inside a job land in that worker's own deque, the idle workers
+        // have to steal them from there.
+        let pool = Arc::new(ThreadPool::new(4));
+        let (sender, receiver) = mpsc::channel();
+        let outer: Vec<_> = (0..10)
+            .map(|i| {
+                let inner_pool = Arc::clone(&pool);
+                let sender = sender.clone();
+                pool.spawn(move || {
+                    for j in 0..10 {
+                        let sender = sender.clone();
+                        inner_pool.execute(move || sender.send(i * 10 + j).unwrap()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        // Joining makes sure no job holds on to the last `Arc`, the pool must not be dropped
+        // on one of its own workers.
+        for handle in outer {
+            handle.join().unwrap();
+        }
+        drop(sender);
+        let mut results: Vec<i32> = receiver.iter().take(100).collect();
+        results.sort();
+        assert_eq!(results, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pool_grows_under_load_and_shrinks_when_idle() {
+        let pool = ThreadPool::builder()
+            .min_threads(1)
+            .max_threads(3)
+            .keep_alive(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        assert_eq!(pool.threads(), 1);
+
+        let (release, wait_release) = mpsc::channel::<()>();
+        let wait_release = Arc::new(Mutex::new(wait_release));
+        let handles: Vec<_> = (0..5)
+            .map(|_| {
+                let wait_release = Arc::clone(&wait_release);
+                pool.spawn(move || {
+                    let _ = wait_release.lock().unwrap().recv();
+                })
+            })
+            .collect();
+        assert_eq!(pool.threads(), 3);
+
+        drop(release);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let started = Instant::now();
+        while pool.threads() > 1 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.threads(), 1);
+    }
+
+    #[test]
+    fn build_rejects_min_above_max() {
+        let result = ThreadPool::builder().min_threads(4).max_threads(2).build();
+        assert!(matches!(result, Err(PoolCreationError::InvalidBounds { min: 4, max: 2 })));
+    }
+
+    #[test]
+    fn scoped_jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(3);
+        let words = ["tiny", "web", "server"];
+        let mut lengths = [0; 3];
+        pool.scope(|s| {
+            for (word, length) in words.iter().zip(&mut lengths) {
+                s.execute(move || *length = word.len()).unwrap();
+            }
+        });
+        assert_eq!(lengths, [4, 3, 6]);
+    }
+
+    #[test]
+    fn scope_waits_for_jobs_and_reports_panics() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.execute(|| panic!("scoped job panicked on purpose")).unwrap();
+                s.execute(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.panicked_jobs(), 1);
+    }
+
+    #[test]
+    fn high_priority_jobs_jump_the_queue() {
+        let pool = ThreadPool::new(1);
+        let (release, wait_release) = mpsc::channel::<()>();
+        pool.execute(move || wait_release.recv().unwrap()).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        for (priority, name) in [(Priority::Normal, "normal"), (Priority::High, "high")] {
+            let sender = sender.clone();
+            pool.execute_with_priority(priority, move || sender.send(name).unwrap()).unwrap();
+        }
+        release.send(()).unwrap();
+        assert_eq!(receiver.recv().unwrap(), "high");
+        assert_eq!(receiver.recv().unwrap(), "normal");
+    }
+
+    #[test]
+    fn delayed_and_periodic_jobs_run_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        let started = Instant::now();
+        pool.execute_after(Duration::from_millis(50), move || sender.send(started.elapsed()).unwrap());
+        assert!(receiver.recv().unwrap() >= Duration::from_millis(50));
+
+        let cancelled = pool.execute_after(Duration::from_millis(20), || panic!("cancelled job ran"));
+        cancelled.cancel();
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let handle = {
+            let ticks = Arc::clone(&ticks);
+            pool.execute_every(Duration::from_millis(10), move || {
+                ticks.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        while ticks.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        handle.cancel();
+        // Give a run that was already queued the chance to finish.
+        thread::sleep(Duration::from_millis(30));
+        let after_cancel = ticks.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(ticks.load(Ordering::SeqCst), after_cancel);
+        assert_eq!(pool.panicked_jobs(), 0);
+    }
+
+    #[test]
+    fn observer_sees_jobs_and_stats_add_up() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let pool = ThreadPool::builder()
+            .size(1)
+            .observer(move |event: &PoolEvent| {
+                let name = match event {
+                    PoolEvent::WorkerStarted { .. } => "started",
+                    PoolEvent::JobFinished { .. } => "finished",
+                    PoolEvent::JobPanicked => "panicked",
+                    PoolEvent::WorkerStopped { .. } => "stopped",
+                    _ => return,
+                };
+                let _ = sender.lock().unwrap().send(name);
+            })
+            .build()
+            .unwrap();
+
+        pool.execute(|| thread::sleep(Duration::from_millis(2))).unwrap();
+        pool.execute(|| panic!("job panicked on purpose")).unwrap();
+        let stats = loop {
+            let stats = pool.stats();
+            if stats.completed_jobs == 2 {
+                break stats;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(stats.threads, 1);
+        assert_eq!(stats.queued_jobs, 0);
+        assert_eq!(stats.panicked_jobs, 1);
+        assert_eq!(stats.run_time.count(), 2);
+        assert!(stats.run_time.percentile(100.0).unwrap() >= Duration::from_millis(2));
+
+        drop(pool);
+        let events: Vec<_> = receiver.iter().collect();
+        assert_eq!(events, ["started", "finished", "finished", "panicked", "stopped"]);
+    }
 }
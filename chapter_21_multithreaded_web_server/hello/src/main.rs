@@ -1,13 +1,39 @@
 use std::{
-    fs,
-    io::{BufReader, prelude::*},
-    net::{TcpListener, TcpStream},
+    env,
+    io::{self, BufReader, ErrorKind, prelude::*},
+    net::TcpListener,
+    path::Path,
+    process,
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    os::unix::net::{UnixListener, UnixStream},
 };
 
 // use threadpool::ThreadPool;
-use hello::ThreadPool;
+use hello::{PoolEvent, RejectionPolicy, ThreadPool};
+use hello::config::{Config, ConfigError, Mode};
+use hello::handler::{Chain, Handler};
+use hello::http::{Request, Response, StatusCode};
+use hello::middleware::{AccessLog, Auth, Cors, ErrorLog, Gzip, RateLimit};
+use hello::proxy::{Proxy, ProxyEvent};
+use hello::template::{Templates, Value};
+use hello::todos::Todos;
+use hello::websocket::{self, Message, Sessions, WebSocket};
+use hello::server::{self, Connection, ConnectionLimits, ConnectionPermit, Shutdown, Socket, TimedStream, Upgraded};
+#[cfg(feature = "async")]
+use hello::async_server;
+#[cfg(feature = "tls")]
+use hello::tls::{self, ServerConfig as TlsConfig};
+
+// Without the `tls` feature there's never a TLS config, so its type can be one with no values.
+#[cfg(not(feature = "tls"))]
+type TlsConfig = std::convert::Infallible;
 
 fn main() {
     #[cfg(any())]
@@ -31,48 +57,558 @@ fn main() {
     // Right now, the server will process each request in turn, meaning it won't process a second connection until the first is finished processing.
     // If the server received more and more requests, this serial execution would be less and less optimal. If the server receives a request that takes a long
     // time to process, subsequent requests will have to wait until the long reuqest is finished, even if the new requests can be processed quickly.
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    
+    // Host, port and the rest come from the command line or the environment now, see `Config`.
+    let config = Config::build(env::args(), |name| env::var(name).ok()).unwrap_or_else(|err| {
+        if err == ConfigError::HelpRequested {
+            println!("{err}");
+            process::exit(0);
+        }
+        eprintln!("Problem parsing arguments: {err}");
+        process::exit(2);
+    });
+    check_mode(&config);
+    let config = Arc::new(config);
+    let app: Arc<dyn Handler> = Arc::new(app(&config));
+    let tls = load_tls(&config);
+
+    let finished = match &config.unix {
+        Some(path) => serve_unix(path, &config, &app, tls),
+        None => serve_tcp(&config, &app, tls),
+    };
+    if !finished {
+        eprintln!("Some connections were still busy after {:?}; exiting anyway.", config.shutdown_timeout);
+    }
+}
+
+// Returns whether every connection finished before `--shutdown-timeout`.
+fn serve_tcp(config: &Arc<Config>, app: &Arc<dyn Handler>, tls: Option<Arc<TlsConfig>>) -> bool {
+    // Binding fails for ordinary reasons (another server already has the port, ports below 1024
+    // need root...), that deserves a message instead of an `unwrap` panic.
+    let listener = TcpListener::bind(config.address()).unwrap_or_else(|err| {
+        let hint = match err.kind() {
+            ErrorKind::AddrInUse => " (the port is already in use, pick another one with --port)",
+            ErrorKind::PermissionDenied => " (ports below 1024 need extra privileges)",
+            _ => "",
+        };
+        eprintln!("Problem listening on {}: {err}{hint}", config.address());
+        process::exit(1);
+    });
+    // With port 0 this is the only way to find out where we ended up.
+    match listener.local_addr() {
+        Ok(addr) => println!("Listening on {}://{addr}", if tls.is_some() { "https" } else { "http" }),
+        Err(err) => eprintln!("Listening, but can't tell where: {err}"),
+    }
+
+    // Ctrl-C (SIGINT) and SIGTERM flip the shutdown flag and wake the accept loop up, so we get
+    // out of it and the pool (or the runtime) actually gets to shut down.
+    let shutdown = Shutdown::new(&listener).unwrap();
+    let handle = shutdown.clone();
+    ctrlc::set_handler(move || handle.trigger()).expect("Error setting the signal handler");
+
+    match config.mode {
+        Mode::Threads => serve_with_pool(listener.incoming(), &shutdown, config, app, tls),
+        Mode::Async => serve_async(listener, &shutdown, config, app),
+    }
+}
+
+// Same as `serve_tcp`, on the Unix domain socket at `path`. Only the pool serves these, see
+// `check_mode`.
+#[cfg(unix)]
+fn serve_unix(path: &Path, config: &Arc<Config>, app: &Arc<dyn Handler>, tls: Option<Arc<TlsConfig>>) -> bool {
+    let listener = bind_unix(path, config.unix_mode).unwrap_or_else(|err| {
+        let hint = match err.kind() {
+            ErrorKind::AddrInUse => " (another server is listening on it)",
+            ErrorKind::PermissionDenied => " (can't write to that directory)",
+            _ => "",
+        };
+        eprintln!("Problem listening on {}: {err}{hint}", path.display());
+        process::exit(1);
+    });
+    println!("Listening on unix:{}", path.display());
+
+    let shutdown = Shutdown::unix(path);
+    let handle = shutdown.clone();
+    ctrlc::set_handler(move || handle.trigger()).expect("Error setting the signal handler");
+
+    let finished = serve_with_pool(listener.incoming(), &shutdown, config, app, tls);
+    // Nobody is listening anymore, the next server shouldn't have to clean up after us.
+    let _ = fs::remove_file(path);
+    finished
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_: &Path, _: &Arc<Config>, _: &Arc<dyn Handler>, _: Option<Arc<TlsConfig>>) -> bool {
+    unreachable!("`check_mode` refuses --unix where there are no Unix domain sockets")
+}
+
+// A server that was killed leaves its socket file behind, and binding to an existing path fails.
+// We remove the file, but only when it's a socket nobody answers on anymore: a live server's
+// socket or some unrelated file are left alone.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, "the file exists and isn't a socket"));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::from(ErrorKind::AddrInUse));
+        }
+        fs::remove_file(path)?;
+    }
+    // Connecting takes write permission on the file, so the mode says who may talk to us. Bound
+    // straight to `path`, the socket would have the umask's permissions until we changed them,
+    // and anyone could connect in between. So it's bound in a directory only we can get into,
+    // gets its mode there, and only then moves into place.
+    let private = path.with_file_name(format!(".hello-{}", process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let temporary = private.join("sock");
+    let bound = UnixListener::bind(&temporary).and_then(|listener| {
+        fs::set_permissions(&temporary, fs::Permissions::from_mode(mode))?;
+        fs::rename(&temporary, path)?;
+        Ok(listener)
+    });
+    // Both are gone already if it worked.
+    let _ = fs::remove_file(&temporary);
+    let _ = fs::remove_dir(&private);
+    bound
+}
+
+// Serves the connections from `incoming` (TCP or Unix domain socket) on the thread pool.
+fn serve_with_pool<S: Socket + Send + 'static>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    shutdown: &Shutdown,
+    config: &Arc<Config>,
+    app: &Arc<dyn Handler>,
+    tls: Option<Arc<TlsConfig>>,
+) -> bool {
     // Is better to create a thread pool, so that we can limit the number of threads that are created.
     // We can use the `threadpool` crate for this. However, in this chapter we'll create our thread pool from scratch to understand how it works.
-    let pool = ThreadPool::new(4);
+    // Traffic comes in bursts, so instead of a fixed 4 workers we keep a couple around and start
+    // more (up to `--workers`) while every worker is busy. The extra ones go away after sitting
+    // idle.
+    let pool = ThreadPool::builder()
+        .min_threads(config.workers.min(2))
+        .max_threads(config.workers)
+        .keep_alive(Duration::from_secs(30))
+        .thread_name("hello-worker")
+        // Under a traffic spike we'd rather tell clients to come back later than queue up
+        // connections (and memory) without limit.
+        .queue_capacity(QUEUE_CAPACITY)
+        .rejection_policy(RejectionPolicy::Reject)
+        .observer(log_pool_event)
+        .build()
+        .unwrap_or_else(|err| {
+            eprintln!("Problem creating the thread pool: {err}");
+            process::exit(1);
+        });
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    let limits = ConnectionLimits::new(config.max_connections, config.max_connections_per_ip);
+    for stream in incoming {
+        if shutdown.is_triggered() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            // Most likely we're out of file descriptors, give the open connections a moment to
+            // close some. Panicking here would take every open connection down with us.
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {e}");
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
         // Multiple infinite threads for each connection (not good for DDOS attacks)
         // thread::spawn(|| {
         //     handle_connection(stream);
         // });
-        pool.execute(move || {
-            handle_connection(stream);
-        })
+        // The pool bounds the threads, but not who gets them: without the caps a single client
+        // could hold every worker with idle connections.
+        let permit = match limits.acquire(stream.remote_addr().map(|addr| addr.ip())) {
+            Ok(permit) => permit,
+            Err(status) => {
+                if tls.is_none() {
+                    reject_connection(stream, status);
+                }
+                continue;
+            }
+        };
+        // If the pool turns the job down, the closure (and the stream inside it) is gone, so we
+        // keep a second handle to the socket around to answer with a 503.
+        let Ok(rejected) = stream.try_clone() else {
+            continue;
+        };
+        let shutdown = shutdown.clone();
+        let config = Arc::clone(config);
+        let app = Arc::clone(app);
+        let tls_config = tls.clone();
+        let accepted = pool.execute(move || {
+            let stream = TimedStream::new(stream, POLL_INTERVAL);
+            match &tls_config {
+                None => handle_connection(stream, permit, &shutdown, &config, &*app),
+                #[cfg(feature = "tls")]
+                Some(tls) => match tls::accept(tls, stream) {
+                    Ok(stream) => handle_connection(stream, permit, &shutdown, &config, &*app),
+                    Err(e) => eprintln!("Failed to start a TLS session: {e}"),
+                },
+                #[cfg(not(feature = "tls"))]
+                Some(never) => match **never {},
+            }
+        });
+        // A plaintext 503 would only confuse a TLS client, those just get disconnected.
+        if accepted.is_err() && tls.is_none() {
+            reject_connection(rejected, StatusCode::ServiceUnavailable);
+        }
+    }
+
+    // Let's now implement the `Thread pool` to be able to handle multiple requests at the same time.
+
+    // We stopped accepting connections, now the requests that are already being served get
+    // `--shutdown-timeout` to finish before we give up on them.
+    println!("Shutting down.");
+    pool.shutdown_timeout(config.shutdown_timeout)
+}
 
+// Same thing on tokio, see `hello::async_server`.
+#[cfg(feature = "async")]
+fn serve_async(listener: TcpListener, shutdown: &Shutdown, config: &Arc<Config>, app: &Arc<dyn Handler>) -> bool {
+    let finished = async_server::serve(listener, shutdown, Arc::clone(config), Arc::clone(app));
+    println!("Shutting down.");
+    finished.unwrap_or_else(|err| {
+        eprintln!("Problem starting the async runtime: {err}");
+        process::exit(1);
+    })
+}
+
+#[cfg(not(feature = "async"))]
+fn serve_async(_: TcpListener, _: &Shutdown, _: &Arc<Config>, _: &Arc<dyn Handler>) -> bool {
+    unreachable!("`check_mode` refuses --mode async without the async feature")
+}
 
-        
+// Async mode needs the `async` feature, and doesn't do TLS or Unix domain sockets (yet).
+fn check_mode(config: &Config) {
+    if config.unix.is_some() && cfg!(not(unix)) {
+        eprintln!("--unix needs Unix domain sockets, this system doesn't have them.");
+        process::exit(1);
+    }
+    if config.mode != Mode::Async {
+        return;
+    }
+    if cfg!(not(feature = "async")) {
+        eprintln!("This server was built without async support, rebuild it with `--features async`.");
+        process::exit(1);
+    }
+    if config.tls_cert.is_some() {
+        eprintln!("--mode async doesn't support TLS, use --mode threads for HTTPS.");
+        process::exit(1);
+    }
+    if config.unix.is_some() {
+        eprintln!("--mode async doesn't support --unix, use --mode threads.");
+        process::exit(1);
     }
+}
 
-    // Let's now implement the `Thread pool` to be able to handle multiple requests at the same time.
+// The pool doesn't print anything on its own anymore. We log workers coming and going, but not
+// every single job.
+fn log_pool_event(event: &PoolEvent) {
+    match event {
+        PoolEvent::WorkerStarted { .. } | PoolEvent::JobFinished { .. } => {}
+        PoolEvent::WorkerRetired { id, idle } => println!("Worker {id} was idle for {idle:?}; retiring."),
+        PoolEvent::WorkerRespawned { id } => println!("Worker {id} died; respawned it."),
+        PoolEvent::SpawnFailed { id, error } => eprintln!("Failed to start worker {id}: {error}"),
+        PoolEvent::WorkerStopped { id } => println!("Worker {id} disconnected; shutting down."),
+        PoolEvent::WorkerDetached { id } => println!("Worker {id} did not finish in time; detaching it."),
+        PoolEvent::JobPanicked => eprintln!("A connection handler panicked."),
+        PoolEvent::JobRejected => eprintln!("Queue full; turned a connection away."),
+    }
+}
+
+fn log_proxy_event(event: &ProxyEvent) {
+    match event {
+        ProxyEvent::ConnectFailed { upstream, error } => {
+            eprintln!("Can't connect to upstream {upstream} ({error}), trying the next one")
+        }
+        ProxyEvent::Failed { upstream, error } => eprintln!("Upstream {upstream} failed: {error}"),
+    }
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&stream);
+// The site itself, wrapped in the middlewares the config asks for. Outermost first: the access
+// log sees every response, including the 401s and CORS preflights answered before `site`.
+fn app(config: &Config) -> Chain {
+    // The pages are templates, see `hello::template`. In `--dev` mode edits show up right away.
+    let templates = Templates::new(&config.root, config.dev);
+    let todos = Todos::new();
+    let sessions = Sessions::new(config.max_websockets);
+    let proxy = config.proxy.as_ref().map(|route| match Proxy::new(&route.upstreams) {
+        Ok(proxy) => (route.clone(), proxy.observer(log_proxy_event)),
+        Err(e) => {
+            eprintln!("Problem setting up the proxy: {e}");
+            process::exit(1);
+        }
+    });
+    let site = move |request: &Request| {
+        // The path goes to the upstream as it is, prefix and all.
+        if let Some((route, proxy)) = &proxy
+            && route.matches(&request.path)
+        {
+            return proxy.handle(request);
+        }
+        if request.path == "/echo" {
+            return websocket::upgrade(request, &sessions, echo);
+        }
+        if Todos::matches(&request.path) {
+            return todos.handle(request);
+        }
+        // Here we check if the request is to / URI, so this response is concrete to that URI.
+        let (status, filename) = if request.method == "GET" && request.path == "/" {
+            (StatusCode::Ok, "hello.html")
+        } else {
+            (StatusCode::NotFound, "404.html")
+        };
+        let context: Value = [("title", "Hello!"), ("path", request.path.as_str())].into_iter().collect();
+        match templates.render(filename, &context) {
+            Ok(html) => Response::html(status, html),
+            Err(e) => {
+                eprintln!("Failed to render {filename}: {e}");
+                Response::text(StatusCode::InternalServerError, "Internal Server Error")
+            }
+        }
+    };
+
+    let mut app = Chain::new(site).with(AccessLog::stdout()).with(ErrorLog::stderr());
+    // Before anything else does work for the request, but after the log so the 429s show up.
+    if let Some(rate) = config.rate_limit {
+        app = app.with(RateLimit::new(rate, rate));
+    }
+    app = app.with(Gzip::default());
+    if config.cors_origins.iter().any(|origin| origin == "*") {
+        app = app.with(Cors::any_origin());
+    } else if !config.cors_origins.is_empty() {
+        app = app.with(Cors::allow_origins(config.cors_origins.clone()));
+    }
+    if let Some(token) = &config.auth_token {
+        app = app.with(Auth::bearer([token.clone()]));
+    }
+    app
+}
+
+// Sends every message straight back, to try WebSockets out with.
+fn echo(mut socket: WebSocket<Upgraded>) {
+    loop {
+        match socket.recv() {
+            Ok(message @ (Message::Text(_) | Message::Binary(_))) => {
+                if socket.send(message).is_err() {
+                    return;
+                }
+            }
+            Ok(Message::Ping(_) | Message::Pong(_)) => {}
+            Ok(Message::Close(_)) | Err(_) => return,
+        }
+    }
+}
+
+// The certificate and key from `--tls-cert`/`--tls-key`, `None` to serve plain HTTP.
+#[cfg(feature = "tls")]
+fn load_tls(config: &Config) -> Option<Arc<TlsConfig>> {
+    let (cert, key) = (config.tls_cert.as_ref()?, config.tls_key.as_ref()?);
+    match tls::server_config(cert, key) {
+        Ok(tls) => Some(tls),
+        Err(e) => {
+            eprintln!("Problem loading the TLS certificate: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn load_tls(config: &Config) -> Option<Arc<TlsConfig>> {
+    if config.tls_cert.is_some() {
+        eprintln!("This server was built without TLS support, rebuild it with `--features tls`.");
+        process::exit(1);
+    }
+    None
+}
+
+// How many accepted connections may wait for a free worker.
+const QUEUE_CAPACITY: usize = 64;
+
+// While a connection is idle we wake up this often to check whether the server is shutting down.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// `permit` counts the connection against the `ConnectionLimits` for as long as it's open.
+fn handle_connection(
+    connection: impl Connection + Send + 'static,
+    permit: ConnectionPermit,
+    shutdown: &Shutdown,
+    config: &Config,
+    app: &dyn Handler,
+) {
+    // Instead of one request per connection, we keep reading requests from the same stream until
+    // the client asks us to close it or stays quiet for longer than `config.keep_alive_timeout`.
+    // While a connection is open it occupies one of the pool's workers, so that timeout can't be
+    // too generous. The `BufReader` lives as long as the connection, so if the client pipelines
+    // several requests the bytes it already buffered are not lost between iterations.
+    // A client that never finishes its request used to pin a worker forever (a handful of them
+    // took the whole server down), now every request has to arrive within
+    // `config.request_timeout`, see `TimedStream`.
+    // Whether it's plain HTTP or TLS, the requests come in through the `BufReader` and the
+    // responses go out through `get_mut()`.
+    let mut buf_reader = BufReader::new(connection);
+    let mut remote_addr = None;
+    if let Some(stream) = buf_reader.get_mut().timed_stream() {
+        let socket = stream.get_ref();
+        if socket.set_write_timeout(Some(config.write_timeout)).is_err() {
+            return;
+        }
+        // A response's head and body can go out in separate writes (a file body is sent straight
+        // from the kernel). With Nagle's algorithm on, the body then waits for the client to ACK
+        // the head, and clients delay their ACKs by up to 40ms.
+        let _ = socket.set_nodelay(true);
+        // Nothing for a Unix domain socket.
+        remote_addr = socket.remote_addr();
+    }
     // let http_request: Vec<_> = buf_reader;
     //     .lines()
     //     .map(|result| result.unwrap())
     //     .take_while(|line| !line.is_empty())
     //     .collect();
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
-    // The first unwrap takes care of the `Option` and stops if no items. The second unwrap takes care of the `Result` and stops if there is an error reading the line.
-    // Here we check if the request is to / URI, so this response is concrete to that URI.
-    let (status_line, filename) = if request_line == "GET / HTTP/1.1" {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
-    };
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
-    let response = format!(
-        "{status_line}\r\nContent-Length: {length}\r\nContent-Type: text/html\r\n\r\n{contents}"
-    );
-    stream.write_all(response.as_bytes()).unwrap();
+    loop {
+        if !wait_for_request(&mut buf_reader, shutdown, config.keep_alive_timeout) {
+            break;
+        }
+        // The clock for the request starts with its first byte.
+        let deadline = Instant::now() + config.request_timeout;
+        set_read_limits(buf_reader.get_mut(), config.read_timeout, Some(deadline));
+        let mut request = match Request::read_with_limits(&mut buf_reader, &config.limits) {
+            Ok(Some(request)) => request,
+            // The client closed the connection.
+            Ok(None) => break,
+            Err(e) => {
+                if let Some(status) = e.status() {
+                    // We don't know where this request ends, so the connection can't be reused.
+                    let response = Response::new(status).with_header("Connection", "close");
+                    let _ = response.write_to(buf_reader.get_mut());
+                }
+                break;
+            }
+        };
+
+        request.remote_addr = remote_addr;
+        let mut response = app.handle(&request);
+
+        // The connection isn't HTTP anymore after this response. It leaves the pool for a
+        // thread of its own, it may stay open for hours.
+        if let Some(upgrade) = response.upgrade.take() {
+            if response.write_to(buf_reader.get_mut()).is_err() {
+                return;
+            }
+            let buffered = buf_reader.buffer().to_vec();
+            server::hand_over(buffered, Box::new(buf_reader.into_inner()), permit, upgrade);
+            return;
+        }
+
+        // During shutdown we still answer the request we already read, but tell the client
+        // this is the last one.
+        let keep_alive = request.keep_alive() && !shutdown.is_triggered();
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        let response = response.with_header("Connection", connection);
+        let written = if request.method == "HEAD" {
+            response.write_head_to(buf_reader.get_mut())
+        } else {
+            response.write_to(buf_reader.get_mut())
+        };
+        if written.is_err() || !keep_alive {
+            break;
+        }
+    }
+    // For TLS this tells the client the response wasn't cut off by an attacker.
+    buf_reader.get_mut().close();
+}
+
+// See `TimedStream`. A connection without one has no limits to change, its reads just block.
+fn set_read_limits(connection: &mut impl Connection, timeout: Duration, deadline: Option<Instant>) {
+    if let Some(stream) = connection.timed_stream() {
+        stream.set_timeout(timeout);
+        stream.set_deadline(deadline);
+    }
+}
+
+// Waits until the first byte of the next request arrives. `fill_buf` doesn't consume anything, so
+// hitting the read timeout here loses no data. Returns `false` if the connection should be closed
+// instead: the client hung up, it was idle for too long or the server is shutting down.
+fn wait_for_request(
+    buf_reader: &mut BufReader<impl Connection>,
+    shutdown: &Shutdown,
+    keep_alive_timeout: Duration,
+) -> bool {
+    set_read_limits(buf_reader.get_mut(), POLL_INTERVAL, None);
+    let idle_since = Instant::now();
+    loop {
+        match buf_reader.fill_buf() {
+            Ok(buffer) => return !buffer.is_empty(),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if shutdown.is_triggered() || idle_since.elapsed() >= keep_alive_timeout {
+                    return false;
+                }
+            }
+            Err(_) => return false,
+        }
+    }
+}
+
+// Runs on the accept loop, so it must not wait on a slow client for long.
+fn reject_connection(mut stream: impl Socket, status: StatusCode) {
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+    let response = Response::new(status)
+        .with_header("Retry-After", "1")
+        .with_header("Connection", "close");
+    let _ = response.write_to(&mut stream);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    // A connection that's just bytes in memory: requests from `input`, responses into `output`.
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Pipe {}
+
+    #[test]
+    fn serves_any_stream() {
+        let input = b"GET /a HTTP/1.1\r\n\r\nHEAD /b HTTP/1.1\r\nConnection: close\r\n\r\n".to_vec();
+        let output = Arc::default();
+        let pipe = Pipe { input: Cursor::new(input), output: Arc::clone(&output) };
+        let permit = ConnectionLimits::new(None, None).acquire(None).unwrap();
+        let shutdown = Shutdown::new(&TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let app = |request: &Request| Response::text(StatusCode::Ok, request.path.clone());
+
+        handle_connection(pipe, permit, &shutdown, &Config::default(), &app);
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\n/a\
+             HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nConnection: close\r\nContent-Length: 2\r\n\r\n"
+        );
+    }
 }
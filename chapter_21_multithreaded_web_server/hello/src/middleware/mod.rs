@@ -0,0 +1,593 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::handler::{Handler, Middleware};
+use crate::http::{Body, Request, Response, StatusCode};
+
+// The middlewares every server ends up needing. They all work on any `Handler`, see `Chain`.
+
+// Writes one line per request in Common Log Format, the one Apache and nginx use by default
+// and every log analyzer understands:
+// 127.0.0.1 - alice [18/Oct/2026:13:55:36 +0000] "GET / HTTP/1.1" 200 175
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(out: impl Write + Send + 'static) -> AccessLog {
+        AccessLog { out: Mutex::new(Box::new(out)) }
+    }
+
+    pub fn stdout() -> AccessLog {
+        AccessLog::new(io::stdout())
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let response = next.handle(request);
+        let host = request.remote_addr.map_or_else(|| String::from("-"), |addr| addr.ip().to_string());
+        let user = basic_credentials(request).map_or_else(|| String::from("-"), |(user, _)| user);
+        // A chunked body's size isn't known until it's sent, CLF writes "-" for that.
+        let bytes = match response.body.len() {
+            Some(len) if response.status.allows_body() => len.to_string(),
+            _ => String::from("-"),
+        };
+        let line = format!(
+            "{host} - {user} [{}] \"{} {} {}\" {} {bytes}\n",
+            clf_date(SystemTime::now()),
+            request.method,
+            request.path,
+            request.version,
+            response.status.code(),
+        );
+        // A log that can't be written mustn't fail the request.
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = out.write_all(line.as_bytes()).and_then(|()| out.flush());
+        response
+    }
+}
+
+// Writes down the `error` of every response that carries one, the reason behind a 500 the
+// client only sees the status of:
+// GET /todos: key must be a string
+pub struct ErrorLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl ErrorLog {
+    pub fn new(out: impl Write + Send + 'static) -> ErrorLog {
+        ErrorLog { out: Mutex::new(Box::new(out)) }
+    }
+
+    pub fn stderr() -> ErrorLog {
+        ErrorLog::new(io::stderr())
+    }
+}
+
+impl Middleware for ErrorLog {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let response = next.handle(request);
+        if let Some(error) = &response.error {
+            let line = format!("{} {}: {error}\n", request.method, request.path);
+            let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+            let _ = out.write_all(line.as_bytes()).and_then(|()| out.flush());
+        }
+        response
+    }
+}
+
+// "18/Oct/2026:13:55:36 +0000". We always log in UTC, that way we don't need the time zone
+// database.
+fn clf_date(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+// Days since 1970-01-01 to (year, month, day), Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// Compresses text responses with gzip when the client says it can take it. Bodies smaller than
+// `min_size` are left alone, the gzip header alone is 18 bytes. Chunked bodies are left alone
+// too, we can't tell how big they'll be. Files and streams (a proxied response) are compressed
+// as they're sent, see `GzipStream`, so they're never all in memory.
+pub struct Gzip {
+    pub min_size: u64,
+    pub level: Compression,
+}
+
+impl Default for Gzip {
+    fn default() -> Gzip {
+        Gzip { min_size: 256, level: Compression::default() }
+    }
+}
+
+impl Middleware for Gzip {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let mut response = next.handle(request);
+        let compressible = response.headers.get("Content-Type").is_some_and(is_compressible);
+        if !compressible || !response.status.allows_body() || response.headers.get("Content-Encoding").is_some() {
+            return response;
+        }
+        // Whether we compress depends on the request, caches must know that. A proxied response
+        // may say so already.
+        let varies = response.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("Vary")
+                && value.split(',').any(|field| field.trim().eq_ignore_ascii_case("Accept-Encoding"))
+        });
+        if !varies {
+            response.headers.append("Vary", "Accept-Encoding");
+        }
+        let accepts_gzip = request.header("Accept-Encoding").is_some_and(accepts_gzip);
+        if !accepts_gzip || response.body.len().is_none_or(|len| len < self.min_size) {
+            return response;
+        }
+
+        let (reader, len) = match std::mem::replace(&mut response.body, Body::Empty) {
+            Body::Bytes(body) => {
+                match gzip(&body, self.level) {
+                    Ok(compressed) if compressed.len() < body.len() => {
+                        response.headers.set("Content-Encoding", "gzip");
+                        response.body = Body::Bytes(compressed);
+                    }
+                    // Already compressed data (or very repetitive headers) can come out bigger.
+                    _ => response.body = Body::Bytes(body),
+                }
+                return response;
+            }
+            Body::File { file, len } => (Box::new(file) as Box<dyn Read + Send>, len),
+            Body::Stream { reader, len } => (reader, len),
+            Body::Empty | Body::Chunked(_) => unreachable!("filtered out by the length check"),
+        };
+        // We can't know the compressed length before we're done, so it goes out chunked.
+        let encoder = Some(GzEncoder::new(Vec::new(), self.level));
+        response.headers.set("Content-Encoding", "gzip");
+        response.body = Body::Chunked(Box::new(GzipStream { reader: reader.take(len), encoder }));
+        response
+    }
+}
+
+// A streamed body, compressed a piece at a time. Each item is whatever the encoder has to show
+// for the next bit of input (often nothing yet), the last one is the rest plus the gzip trailer.
+struct GzipStream {
+    reader: io::Take<Box<dyn Read + Send>>,
+    // `None` once we're done.
+    encoder: Option<GzEncoder<Vec<u8>>>,
+}
+
+impl Iterator for GzipStream {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let encoder = self.encoder.as_mut()?;
+        let mut buffer = [0; 16 * 1024];
+        let result = match self.reader.read(&mut buffer) {
+            // The body ended before its length said it would, finishing would hide that.
+            Ok(0) if self.reader.limit() > 0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(0) => return self.encoder.take().map(GzEncoder::finish),
+            Ok(read) => encoder.write_all(&buffer[..read]).map(|()| std::mem::take(encoder.get_mut())),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.encoder = None;
+        }
+        Some(result)
+    }
+}
+
+fn gzip(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+// Images, videos and archives are compressed already.
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime.starts_with("text/") || matches!(mime, "application/json" | "application/javascript" | "image/svg+xml")
+}
+
+// "gzip, deflate, br" or "gzip;q=0.8, *;q=0.1". `gzip;q=0` means "anything but gzip".
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    let mut wildcard = false;
+    for coding in accept_encoding.split(',') {
+        let mut parts = coding.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case("gzip") {
+            return quality > 0.0;
+        }
+        if name == "*" {
+            wildcard = quality > 0.0;
+        }
+    }
+    wildcard
+}
+
+// Lets browsers call us from pages on other origins. Without these headers a browser still
+// sends simple requests, but hides the response from the page, and refuses to send anything
+// else at all.
+pub struct Cors {
+    // Empty means any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: String,
+    pub allowed_headers: String,
+    // How long a browser may cache the answer to a preflight request.
+    pub max_age: Duration,
+}
+
+impl Cors {
+    pub fn any_origin() -> Cors {
+        Cors {
+            allowed_origins: Vec::new(),
+            allowed_methods: String::from("GET, POST, PUT, PATCH, DELETE, OPTIONS"),
+            allowed_headers: String::from("Authorization, Content-Type"),
+            max_age: Duration::from_secs(600),
+        }
+    }
+
+    pub fn allow_origins(origins: impl IntoIterator<Item = impl Into<String>>) -> Cors {
+        Cors { allowed_origins: origins.into_iter().map(Into::into).collect(), ..Cors::any_origin() }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins.is_empty() || self.allowed_origins.iter().any(|allowed| allowed == origin)
+    }
+
+    fn respond(&self, request: &Request, next: &dyn Handler) -> Response {
+        // Same-origin requests and non-browser clients don't send `Origin`. For an origin we
+        // don't allow we add nothing, and the browser does the blocking.
+        let origin = match request.header("Origin") {
+            Some(origin) if self.allows(origin) => origin.to_string(),
+            _ => return next.handle(request),
+        };
+        let allow_origin = if self.allowed_origins.is_empty() { String::from("*") } else { origin };
+
+        // The browser asks before sending anything that isn't a "simple" request. The
+        // preflight is ours to answer, the handler never sees it.
+        let mut response = if request.method == "OPTIONS" && request.header("Access-Control-Request-Method").is_some() {
+            Response::new(StatusCode::NoContent)
+                .with_header("Access-Control-Allow-Methods", self.allowed_methods.as_str())
+                .with_header("Access-Control-Allow-Headers", self.allowed_headers.as_str())
+                .with_header("Access-Control-Max-Age", self.max_age.as_secs().to_string())
+        } else {
+            next.handle(request)
+        };
+        response.headers.set("Access-Control-Allow-Origin", allow_origin);
+        response
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let mut response = self.respond(request, next);
+        // With an allow-list every response depends on `Origin`, including the ones without CORS
+        // headers. Otherwise a cache could keep the answer to a disallowed origin (or to no
+        // origin at all) and give it to an allowed one.
+        if !self.allowed_origins.is_empty() {
+            response.headers.append("Vary", "Origin");
+        }
+        response
+    }
+}
+
+// Turns away requests without valid credentials with a 401, before they reach the handler.
+pub enum Auth {
+    // "Authorization: Basic base64(user:password)", checked against a fixed list of users.
+    Basic { realm: String, users: Vec<(String, String)> },
+    // "Authorization: Bearer <token>", checked against a fixed list of tokens.
+    Bearer { tokens: Vec<String> },
+}
+
+impl Auth {
+    pub fn basic(realm: impl Into<String>, users: impl IntoIterator<Item = (String, String)>) -> Auth {
+        Auth::Basic { realm: realm.into(), users: users.into_iter().collect() }
+    }
+
+    pub fn bearer(tokens: impl IntoIterator<Item = impl Into<String>>) -> Auth {
+        Auth::Bearer { tokens: tokens.into_iter().map(Into::into).collect() }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        match self {
+            Auth::Basic { users, .. } => basic_credentials(request).is_some_and(|(user, password)| {
+                users.iter().any(|(known_user, known_password)| {
+                    // `&` instead of `&&`, so a wrong user takes as long as a wrong password.
+                    constant_time_eq(known_user, &user) & constant_time_eq(known_password, &password)
+                })
+            }),
+            Auth::Bearer { tokens } => bearer_token(request)
+                .is_some_and(|token| tokens.iter().any(|known| constant_time_eq(known, token))),
+        }
+    }
+
+    fn challenge(&self) -> String {
+        match self {
+            Auth::Basic { realm, .. } => format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+            Auth::Bearer { .. } => String::from("Bearer"),
+        }
+    }
+}
+
+impl Middleware for Auth {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        if self.authorized(request) {
+            return next.handle(request);
+        }
+        Response::text(StatusCode::Unauthorized, "Unauthorized").with_header("WWW-Authenticate", self.challenge())
+    }
+}
+
+// The user and password from "Authorization: Basic ...", if that's what the request has.
+fn basic_credentials(request: &Request) -> Option<(String, String)> {
+    let (scheme, encoded) = request.header("Authorization")?.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+fn bearer_token(request: &Request) -> Option<&str> {
+    let (scheme, token) = request.header("Authorization")?.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("Bearer").then(|| token.trim())
+}
+
+// Compares without stopping at the first difference, so the time it takes doesn't tell an
+// attacker how much of their guess was right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Token buckets per client IP: a client can send `burst` requests in a row, after that
+// `per_second` more every second. Past that it gets 429s, with `Retry-After` saying when it may
+// try again. Requests we don't know the address of aren't limited.
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    // Tokens left, as of the `Instant`.
+    by_ip: HashMap<IpAddr, (f64, Instant)>,
+    // Once there are this many we drop the full ones, see `take`.
+    prune_at: usize,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32, burst: u32) -> RateLimit {
+        let buckets = Buckets { by_ip: HashMap::new(), prune_at: 1024 };
+        RateLimit { per_second: f64::from(per_second), burst: f64::from(burst), buckets: Mutex::new(buckets) }
+    }
+
+    // Takes one of `ip`'s tokens, or says how long until it has one.
+    fn take(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let refilled = |(tokens, since): (f64, Instant)| {
+            (tokens + now.duration_since(since).as_secs_f64() * self.per_second).min(self.burst)
+        };
+        // Every address that ever sent us a request would stay in here. A full bucket is the
+        // same as none, so those can go.
+        if buckets.by_ip.len() >= buckets.prune_at {
+            buckets.by_ip.retain(|_, bucket| refilled(*bucket) < self.burst);
+            buckets.prune_at = (buckets.by_ip.len() * 2).max(1024);
+        }
+        let bucket = buckets.by_ip.entry(ip).or_insert((self.burst, now));
+        let tokens = refilled(*bucket);
+        if tokens >= 1.0 {
+            *bucket = (tokens - 1.0, now);
+            Ok(())
+        } else {
+            *bucket = (tokens, now);
+            Err(Duration::from_secs_f64((1.0 - tokens) / self.per_second))
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Response {
+        let Some(addr) = request.remote_addr else {
+            return next.handle(request);
+        };
+        match self.take(addr.ip(), Instant::now()) {
+            Ok(()) => next.handle(request),
+            // Whole seconds, rounded up, so coming back then works.
+            Err(wait) => Response::text(StatusCode::TooManyRequests, "Too Many Requests")
+                .with_header("Retry-After", wait.as_secs_f64().ceil().max(1.0).to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::Chain;
+    use flate2::read::GzDecoder;
+
+    fn request(raw: &str) -> Request {
+        Request::read_from(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn page(_: &Request) -> Response {
+        Response::html(StatusCode::Ok, "<p>hello</p>".repeat(100))
+    }
+
+    // A log we can read back.
+    #[derive(Clone, Default)]
+    struct Shared(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn access_log_uses_common_log_format() {
+        assert_eq!(clf_date(UNIX_EPOCH + Duration::from_secs(1_792_330_536)), "18/Oct/2026:13:35:36 +0000");
+
+        let log = Shared::default();
+        let app = Chain::new(page).with(AccessLog::new(log.clone()));
+        app.handle(&request("GET /x HTTP/1.1\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n"));
+        let line = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(line.starts_with("- - alice ["), "{line}");
+        assert!(line.ends_with("] \"GET /x HTTP/1.1\" 200 1200\n"), "{line}");
+    }
+
+    #[test]
+    fn error_log_writes_down_why_it_was_a_500() {
+        let log = Shared::default();
+        // JSON objects only have string keys.
+        let broken = |_: &Request| Response::json(StatusCode::Ok, &HashMap::from([((1, 2), 3)]));
+        let app = Chain::new(broken).with(ErrorLog::new(log.clone()));
+        let response = app.handle(&request("GET /broken HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::InternalServerError);
+        let line = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(line, "GET /broken: key must be a string\n");
+
+        // Nothing to write down.
+        Chain::new(page).with(ErrorLog::new(log.clone())).handle(&request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(log.0.lock().unwrap().len(), line.len());
+    }
+
+    #[test]
+    fn gzip_is_negotiated() {
+        let app = Chain::new(page).with(Gzip::default());
+
+        let plain = app.handle(&request("GET / HTTP/1.1\r\nAccept-Encoding: gzip;q=0, br\r\n\r\n"));
+        assert_eq!(plain.headers.get("Content-Encoding"), None);
+        assert_eq!(plain.headers.get("Vary"), Some("Accept-Encoding"));
+
+        let compressed = app.handle(&request("GET / HTTP/1.1\r\nAccept-Encoding: deflate, gzip\r\n\r\n"));
+        assert_eq!(compressed.headers.get("Content-Encoding"), Some("gzip"));
+        let Body::Bytes(bytes) = compressed.body else { panic!("expected bytes") };
+        let mut html = String::new();
+        GzDecoder::new(&bytes[..]).read_to_string(&mut html).unwrap();
+        assert_eq!(html, "<p>hello</p>".repeat(100));
+    }
+
+    #[test]
+    fn streamed_bodies_are_compressed_as_they_go() {
+        let html = "<p>hello</p>".repeat(10_000);
+        let streamed = {
+            let html = html.clone();
+            move |_: &Request| {
+                let len = html.len() as u64;
+                let reader: Box<dyn Read + Send> = Box::new(io::Cursor::new(html.clone().into_bytes()));
+                Response::new(StatusCode::Ok)
+                    .with_header("Content-Type", "text/html")
+                    .with_body(Body::Stream { reader, len })
+            }
+        };
+        let app = Chain::new(streamed).with(Gzip::default());
+
+        let response = app.handle(&request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        let Body::Chunked(chunks) = response.body else { panic!("expected a chunked body") };
+        let compressed: Vec<u8> = chunks.flat_map(Result::unwrap).collect();
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, html);
+
+        // Files too, they're never read whole.
+        let path = std::env::temp_dir().join(format!("hello-gzip-{}.html", std::process::id()));
+        std::fs::write(&path, &html).unwrap();
+        let served = {
+            let path = path.clone();
+            move |_: &Request| Response::file(StatusCode::Ok, &path).unwrap()
+        };
+        let app = Chain::new(served).with(Gzip::default());
+        let response = app.handle(&request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        let Body::Chunked(chunks) = response.body else { panic!("expected a chunked body") };
+        let compressed: Vec<u8> = chunks.flat_map(Result::unwrap).collect();
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, html);
+    }
+
+    #[test]
+    fn cors_answers_preflight_requests() {
+        let app = Chain::new(page).with(Cors::allow_origins(["https://example.com"]));
+
+        let preflight = app.handle(&request(
+            "OPTIONS /todos HTTP/1.1\r\nOrigin: https://example.com\r\nAccess-Control-Request-Method: PUT\r\n\r\n",
+        ));
+        assert_eq!(preflight.status, StatusCode::NoContent);
+        assert_eq!(preflight.headers.get("Access-Control-Allow-Origin"), Some("https://example.com"));
+        assert!(preflight.headers.get("Access-Control-Allow-Methods").unwrap().contains("PUT"));
+
+        let other = app.handle(&request("GET / HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n"));
+        assert_eq!(other.headers.get("Access-Control-Allow-Origin"), None);
+        assert_eq!(other.headers.get("Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn rate_limit_gives_each_client_a_bucket() {
+        let app = Chain::new(page).with(RateLimit::new(1, 2));
+        let from = |ip: &str| {
+            let mut request = request("GET / HTTP/1.1\r\n\r\n");
+            request.remote_addr = Some(format!("{ip}:40000").parse().unwrap());
+            app.handle(&request)
+        };
+
+        assert_eq!(from("192.0.2.1").status, StatusCode::Ok);
+        assert_eq!(from("192.0.2.1").status, StatusCode::Ok);
+        let limited = from("192.0.2.1");
+        assert_eq!(limited.status, StatusCode::TooManyRequests);
+        assert_eq!(limited.headers.get("Retry-After"), Some("1"));
+        assert_eq!(from("192.0.2.2").status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn auth_checks_credentials() {
+        let basic = Chain::new(page).with(Auth::basic("hello", [(String::from("alice"), String::from("secret"))]));
+        // alice:secret and alice:wrong
+        let ok = basic.handle(&request("GET / HTTP/1.1\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n"));
+        assert_eq!(ok.status, StatusCode::Ok);
+        let denied = basic.handle(&request("GET / HTTP/1.1\r\nAuthorization: Basic YWxpY2U6d3Jvbmc=\r\n\r\n"));
+        assert_eq!(denied.status, StatusCode::Unauthorized);
+        assert_eq!(denied.headers.get("WWW-Authenticate"), Some("Basic realm=\"hello\", charset=\"UTF-8\""));
+
+        let bearer = Chain::new(page).with(Auth::bearer(["t0ken"]));
+        assert_eq!(bearer.handle(&request("GET / HTTP/1.1\r\nAuthorization: Bearer t0ken\r\n\r\n")).status, StatusCode::Ok);
+        assert_eq!(bearer.handle(&request("GET / HTTP/1.1\r\n\r\n")).status, StatusCode::Unauthorized);
+    }
+}
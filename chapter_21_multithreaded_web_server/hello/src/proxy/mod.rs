@@ -0,0 +1,437 @@
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::client::{BodyLength, ClientError, Chunks, ResponseHead};
+use crate::handler::Handler;
+use crate::http::{Body, Request, Response, StatusCode};
+
+// A reverse proxy: a handler that answers by passing the request on to another server (the
+// upstream) and its response back to the client.
+//
+// let api = Proxy::new(&["10.0.0.5:8080", "10.0.0.6:8080"])?;
+//
+// With several upstreams each request goes to the next one in turn. There are no active health
+// checks, the requests themselves are the checks: an upstream we couldn't talk to `max_failures`
+// times in a row is left alone for `cooldown`. After that it's half-open: one request at a time
+// goes to it as a probe, the others skip it, until a probe succeeds and it's up again.
+//
+// Every request gets a new connection to the upstream, closed after the response. The response
+// body is streamed: the client gets it as the upstream sends it, we never hold all of it.
+//
+// The request body is NOT streamed, only the response body is. Both servers read a request
+// whole, body included, before any handler sees it (up to `Limits::max_body_bytes`), and a
+// `Request` has no way to hand a handler a body it hasn't read yet. Until it does, the proxy
+// writes the body on in one piece, and `max_request_body` caps how big that piece may be:
+// bigger requests get a 413 without bothering an upstream.
+//
+// What goes wrong with an upstream isn't printed here, an `observer` gets told, see `ProxyEvent`.
+pub struct Proxy {
+    upstreams: Vec<Upstream>,
+    // Where the next request starts looking for an upstream that's up.
+    next: AtomicUsize,
+    // For connecting, and for every single read and write after that.
+    pub timeout: Duration,
+    pub max_failures: u32,
+    pub cooldown: Duration,
+    pub max_request_body: usize,
+    observer: Option<Arc<dyn ProxyObserver>>,
+}
+
+// Called on the thread that handles the request, like a `PoolObserver`. Any `Fn(&ProxyEvent)`
+// closure works:
+// Proxy::new(&["10.0.0.5:8080"])?.observer(|event: &ProxyEvent| eprintln!("{event:?}"))
+pub trait ProxyObserver: Send + Sync {
+    fn on_event(&self, event: &ProxyEvent);
+}
+
+impl<F> ProxyObserver for F
+where
+    F: Fn(&ProxyEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &ProxyEvent) {
+        self(event)
+    }
+}
+
+#[derive(Debug)]
+pub enum ProxyEvent {
+    // We couldn't connect, the request goes to the next upstream.
+    ConnectFailed { upstream: String, error: io::Error },
+    // Something went wrong after we connected, the client gets a 502 or 504.
+    Failed { upstream: String, error: ClientError },
+}
+
+struct Upstream {
+    addr: SocketAddr,
+    // What we resolved `addr` from, for the `Host` header.
+    host: String,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    // In a row, any success starts over.
+    failures: u32,
+    down_until: Option<Instant>,
+    // A request is on its way to the upstream after its cooldown, see `take_turn`.
+    probing: bool,
+}
+
+impl Upstream {
+    fn health(&self) -> MutexGuard<'_, Health> {
+        self.health.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Whether a request may go to it now. Once the cooldown is over only one request at a time
+    // does, the one that gets `true` is the probe.
+    fn take_turn(&self, now: Instant) -> bool {
+        let mut health = self.health();
+        match health.down_until {
+            None => true,
+            Some(until) if now >= until && !health.probing => {
+                health.probing = true;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    fn succeeded(&self) {
+        *self.health() = Health::default();
+    }
+
+    // Past `max_failures` every failure takes it down again, so an upstream that's still broken
+    // after the cooldown only gets the one probe.
+    fn failed(&self, max_failures: u32, cooldown: Duration) {
+        let mut health = self.health();
+        health.failures += 1;
+        health.probing = false;
+        if health.failures >= max_failures {
+            health.down_until = Some(Instant::now() + cooldown);
+        }
+    }
+}
+
+impl Proxy {
+    // Resolves every upstream once, up front. A name that resolves to several addresses uses
+    // the first.
+    pub fn new(upstreams: &[impl AsRef<str>]) -> io::Result<Proxy> {
+        let upstreams = upstreams
+            .iter()
+            .map(|host| {
+                let host = host.as_ref();
+                let addr = host.to_socket_addrs()?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("{host} doesn't resolve to anything"))
+                })?;
+                Ok(Upstream { addr, host: host.to_string(), health: Mutex::default() })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        if upstreams.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a proxy needs at least one upstream"));
+        }
+        Ok(Proxy {
+            upstreams,
+            next: AtomicUsize::new(0),
+            timeout: Duration::from_secs(10),
+            max_failures: 3,
+            cooldown: Duration::from_secs(10),
+            max_request_body: 1024 * 1024,
+            observer: None,
+        })
+    }
+
+    // Gets told about every upstream that failed a request, see `ProxyEvent`.
+    pub fn observer(mut self, observer: impl ProxyObserver + 'static) -> Proxy {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    fn notify(&self, event: ProxyEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    // Sends `request` to `upstream` and reads the head of its response. Errors before we
+    // connected are `Exchange::Connect`, the request never got anywhere, so another upstream can
+    // have it.
+    fn exchange(&self, upstream: &Upstream, request: &Request) -> Result<Response, Exchange> {
+        let stream = TcpStream::connect_timeout(&upstream.addr, self.timeout).map_err(Exchange::Connect)?;
+        let result = (|| {
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            stream.set_nodelay(true)?;
+            let mut message = request_head(upstream, request).into_bytes();
+            message.extend_from_slice(&request.body);
+            (&stream).write_all(&message)?;
+            response(BufReader::new(stream), request.method == "HEAD")
+        })();
+        result.map_err(Exchange::Failed)
+    }
+}
+
+// How `exchange` failed.
+enum Exchange {
+    Connect(io::Error),
+    Failed(ClientError),
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: &Request) -> Response {
+        if request.body.len() > self.max_request_body {
+            return Response::text(StatusCode::ContentTooLarge, StatusCode::ContentTooLarge.reason());
+        }
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        // Each upstream that's up gets at most one try, starting with the one whose turn it is.
+        for offset in 0..self.upstreams.len() {
+            let upstream = &self.upstreams[(start + offset) % self.upstreams.len()];
+            if !upstream.take_turn(now) {
+                continue;
+            }
+            match self.exchange(upstream, request) {
+                Ok(response) => {
+                    upstream.succeeded();
+                    return response;
+                }
+                Err(Exchange::Connect(error)) => {
+                    upstream.failed(self.max_failures, self.cooldown);
+                    self.notify(ProxyEvent::ConnectFailed { upstream: upstream.host.clone(), error });
+                }
+                // It may have acted on the request already, sending it again isn't safe.
+                Err(Exchange::Failed(error)) => {
+                    upstream.failed(self.max_failures, self.cooldown);
+                    let timed_out = matches!(
+                        &error,
+                        ClientError::Io(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+                    );
+                    let status = if timed_out { StatusCode::GatewayTimeout } else { StatusCode::BadGateway };
+                    self.notify(ProxyEvent::Failed { upstream: upstream.host.clone(), error });
+                    return Response::text(status, status.reason());
+                }
+            }
+        }
+        Response::text(StatusCode::ServiceUnavailable, "Service Unavailable")
+            .with_header("Retry-After", self.cooldown.as_secs().max(1).to_string())
+    }
+}
+
+// Headers that are about one connection, not the message, so they aren't passed on. `Connection`
+// can name more of them.
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+fn is_hop_by_hop(name: &str, connection: Option<&str>) -> bool {
+    HOP_BY_HOP.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+        || connection.is_some_and(|connection| connection.split(',').any(|token| token.trim().eq_ignore_ascii_case(name)))
+}
+
+// The request as the upstream sees it: `Host` is the upstream, the client's `Host` moves to
+// `X-Forwarded-Host`, and the client's address is added to `X-Forwarded-For`.
+fn request_head(upstream: &Upstream, request: &Request) -> String {
+    let connection = request.header("Connection");
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, request.path, upstream.host);
+    let mut forwarded_for = Vec::new();
+    for (name, value) in &request.headers {
+        let name = name.as_str();
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for.push(value.as_str());
+            continue;
+        }
+        // We write the body's length ourselves, and there's nobody to send a 100 Continue to,
+        // the server read the whole body already.
+        let ours = ["Host", "X-Forwarded-Host", "Content-Length", "Expect"];
+        if is_hop_by_hop(name, connection) || ours.iter().any(|own| own.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    let client_ip = request.remote_addr.map(|addr| addr.ip().to_string());
+    forwarded_for.extend(client_ip.as_deref());
+    if !forwarded_for.is_empty() {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for.join(", ")));
+    }
+    if let Some(host) = request.header("Host") {
+        head.push_str(&format!("X-Forwarded-Host: {host}\r\n"));
+    }
+    if !request.body.is_empty() || matches!(request.method.as_str(), "POST" | "PUT" | "PATCH") {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head
+}
+
+// Reads the upstream's response head and turns it into ours, with a body that reads the rest
+// from `reader` as it's sent.
+fn response(mut reader: BufReader<TcpStream>, head_request: bool) -> Result<Response, ClientError> {
+    let mut head = ResponseHead::read_from(&mut reader)?;
+    // We never ask for 100 Continue, but a server may send interim responses anyway. The one
+    // that counts comes after them. (No 101 either, `Upgrade` isn't passed on.)
+    while (100..200).contains(&head.status) {
+        head = ResponseHead::read_from(&mut reader)?;
+    }
+
+    let mut response = Response::new(StatusCode::from_code(head.status));
+    let connection = head.headers.get("Connection");
+    for (name, value) in head.headers.iter() {
+        // `Response` sets the framing headers for the body we give it.
+        if !is_hop_by_hop(name, connection) && !name.eq_ignore_ascii_case("Content-Length") {
+            response.headers.append(name, value);
+        }
+    }
+    response.body = match BodyLength::of(head.status, &head.headers, head_request)? {
+        BodyLength::Empty => Body::Empty,
+        BodyLength::Exactly(len) => Body::Stream { reader: Box::new(reader), len },
+        BodyLength::Chunked => {
+            let chunks = Chunks::new(reader).map(|chunk| chunk.map_err(io::Error::other));
+            Body::Chunked(Box::new(chunks))
+        }
+        // We don't know the length either, so it goes to the client chunked.
+        BodyLength::UntilClose => Body::Chunked(Box::new(UntilClose(reader))),
+    };
+    Ok(response)
+}
+
+// A body that ends when the connection does, in whatever pieces the socket gives us.
+struct UntilClose(BufReader<TcpStream>);
+
+impl Iterator for UntilClose {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let mut buffer = vec![0; 16 * 1024];
+        match self.0.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some(Ok(buffer))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientResponse;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    // An upstream that answers every connection with `response` and sends us the head of each
+    // request it got.
+    fn upstream(response: &'static str) -> (SocketAddr, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, heads) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") && reader.read_line(&mut head).unwrap() > 0 {}
+                stream.write_all(response.as_bytes()).unwrap();
+                if sender.send(head).is_err() {
+                    return;
+                }
+            }
+        });
+        (addr, heads)
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let mut request = Request::read_from(&mut "GET /api/items?page=2 HTTP/1.1\r\n\r\n".as_bytes()).unwrap().unwrap();
+        request.headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        request.remote_addr = Some("192.0.2.7:50000".parse().unwrap());
+        request
+    }
+
+    // What the client would get.
+    fn send(response: Response) -> ClientResponse {
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes).unwrap();
+        ClientResponse::read_from(&mut &bytes[..], false).unwrap()
+    }
+
+    #[test]
+    fn rewrites_headers_and_streams_the_response() {
+        let (addr, heads) = upstream(
+            "HTTP/1.1 200 OK\r\nConnection: close, X-Secret\r\nX-Secret: 1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n",
+        );
+        let proxy = Proxy::new(&[addr.to_string()]).unwrap();
+        let response = proxy.handle(&request(&[
+            ("Host", "example.com"),
+            ("X-Forwarded-For", "203.0.113.1"),
+            ("Connection", "keep-alive, X-Hop"),
+            ("X-Hop", "1"),
+            ("Accept", "*/*"),
+        ]));
+        let response = send(response);
+        assert_eq!(response.text(), "abcdef");
+        assert_eq!(response.header("X-Secret"), None);
+
+        let head = heads.recv().unwrap();
+        assert!(head.starts_with(&format!("GET /api/items?page=2 HTTP/1.1\r\nHost: {addr}\r\n")), "{head}");
+        assert!(head.contains("Accept: */*\r\n"), "{head}");
+        assert!(head.contains("X-Forwarded-For: 203.0.113.1, 192.0.2.7\r\n"), "{head}");
+        assert!(head.contains("X-Forwarded-Host: example.com\r\n"), "{head}");
+        assert!(head.contains("Connection: close\r\n"), "{head}");
+        assert!(!head.contains("X-Hop") && !head.contains("keep-alive"), "{head}");
+    }
+
+    #[test]
+    fn takes_turns_and_skips_upstreams_that_are_down() {
+        let (first, _first_heads) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst");
+        let (second, _second_heads) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecond");
+        // Nothing listens there anymore.
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut proxy = Proxy::new(&[first.to_string(), dead.to_string(), second.to_string()]).unwrap();
+        proxy.max_failures = 2;
+
+        let bodies: Vec<String> = (0..6).map(|_| send(proxy.handle(&request(&[]))).text()).collect();
+        assert_eq!(bodies, ["first", "second", "second", "first", "second", "second"]);
+        assert!(!proxy.upstreams[1].take_turn(Instant::now()));
+        // After the cooldown a single request gets through, until it's known how that went.
+        let later = Instant::now() + proxy.cooldown;
+        assert!(proxy.upstreams[1].take_turn(later));
+        assert!(!proxy.upstreams[1].take_turn(later));
+        proxy.upstreams[1].failed(proxy.max_failures, proxy.cooldown);
+        assert!(!proxy.upstreams[1].take_turn(later));
+        proxy.upstreams[1].succeeded();
+        assert!(proxy.upstreams[1].take_turn(Instant::now()));
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        let proxy = Proxy::new(&[dead.to_string()]).unwrap().observer(move |event: &ProxyEvent| {
+            seen.lock().unwrap().push(format!("{event:?}"));
+        });
+        let response = proxy.handle(&request(&[]));
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("ConnectFailed"), "{events:?}");
+        assert_eq!(response.status, StatusCode::ServiceUnavailable);
+        assert_eq!(response.headers.get("Retry-After"), Some("10"));
+
+        // Too big to pass on, no upstream is even asked.
+        let mut proxy = Proxy::new(&[first.to_string()]).unwrap();
+        proxy.max_request_body = 4;
+        let mut big = request(&[]);
+        big.body = b"hello".to_vec();
+        assert_eq!(proxy.handle(&big).status, StatusCode::ContentTooLarge);
+    }
+}
@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::iter;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
+use crate::{Job, Priority};
+
+// The pool used to share one `Arc<Mutex<mpsc::Receiver<Job>>>` between all workers, so every
+// single `recv` went through the same lock. Now every worker has its own deque and there is one
+// global "injector" queue:
+// - Jobs submitted from outside the pool go into the injector.
+// - Jobs submitted by a job that runs on one of our workers go into that worker's own deque.
+// - A worker looking for work first pops from its own deque, then grabs a whole batch from the
+//   injector (so it doesn't have to come back for every tiny job), and if that's empty too it
+//   steals from the other workers' deques.
+// High priority jobs skip all of that and go into a second injector that every worker checks
+// before anything else.
+// The deques are lock-free (crossbeam-deque), the only lock left is the one idle workers sleep
+// on.
+pub(crate) struct JobQueue {
+    injector: Injector<Queued>,
+    urgent: Injector<Queued>,
+    // One per worker id, so other workers can steal from it.
+    stealers: RwLock<Vec<Stealer<Queued>>>,
+    // Jobs that were submitted but haven't started yet, wherever they are.
+    queued: AtomicUsize,
+    // Workers that found nothing to do, went to sleep and haven't been woken up for a job yet.
+    // Only changed while holding `sleep`, but `push` reads it without the lock to skip the lock
+    // entirely when nobody is asleep.
+    idle: AtomicUsize,
+    // Workers that were woken up and are looking for a job right now. While there is one, a new
+    // job doesn't wake anybody else: the searcher will find it, and when it does it wakes the next
+    // sleeper if there's more work. That way a burst of tiny jobs doesn't turn into a wake-up
+    // (and a context switch) per job.
+    searching: AtomicUsize,
+    capacity: Option<usize>,
+    closed: AtomicBool,
+    // How many wake-ups were handed out that no worker has picked up yet.
+    sleep: Mutex<usize>,
+    wake: Condvar,
+    // Where `push_blocking` waits for a worker to take a job off a full queue.
+    space_lock: Mutex<()>,
+    space: Condvar,
+}
+
+// A job and when it was submitted, so the pool can tell how long it waited.
+pub(crate) struct Queued {
+    pub(crate) job: Job,
+    pub(crate) queued_at: Instant,
+}
+
+// Why `next_job` returned.
+pub(crate) enum Next {
+    Job(Queued),
+    // The worker found nothing to do for the whole idle timeout.
+    Idle,
+    // The queue is closed and empty.
+    Closed,
+}
+
+// We should never miss a wake-up, but if we ever do this is the most a job waits because of it.
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
+thread_local! {
+    // The deque of the worker running on this thread, together with the address of the queue it
+    // belongs to, so a job submitting to a *different* pool doesn't end up in our deque.
+    static LOCAL: RefCell<Option<(usize, Worker<Queued>)>> = const { RefCell::new(None) };
+}
+
+impl JobQueue {
+    pub(crate) fn new(capacity: Option<usize>) -> JobQueue {
+        JobQueue {
+            injector: Injector::new(),
+            urgent: Injector::new(),
+            stealers: RwLock::new(Vec::new()),
+            queued: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            searching: AtomicUsize::new(0),
+            capacity,
+            closed: AtomicBool::new(false),
+            sleep: Mutex::new(0),
+            wake: Condvar::new(),
+            space_lock: Mutex::new(()),
+            space: Condvar::new(),
+        }
+    }
+
+    // Creates the deque for worker `id`. If a worker with that id existed before (it died and is
+    // being replaced), the jobs still sitting in its deque move to the injector.
+    pub(crate) fn register(&self, id: usize) -> Worker<Queued> {
+        let deque = Worker::new_fifo();
+        let mut stealers = self.stealers.write().unwrap_or_else(PoisonError::into_inner);
+        if id < stealers.len() {
+            let old = std::mem::replace(&mut stealers[id], deque.stealer());
+            while let Steal::Success(job) = retry(|| old.steal()) {
+                self.injector.push(job);
+            }
+        } else {
+            stealers.push(deque.stealer());
+        }
+        deque
+    }
+
+    // Must be called on the worker's own thread before `next_job`.
+    pub(crate) fn attach(&self, deque: Worker<Queued>) {
+        LOCAL.with(|local| *local.borrow_mut() = Some((self.address(), deque)));
+    }
+
+    // Jobs waiting to be started.
+    pub(crate) fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    // Workers that will pick up the next job right away: the ones asleep and the ones that were
+    // just woken up and are looking for work.
+    pub(crate) fn available_workers(&self) -> usize {
+        self.idle.load(Ordering::SeqCst) + self.searching.load(Ordering::SeqCst)
+    }
+
+    // Adds the job if there's room. With a capacity, at most `capacity` jobs wait on top of the
+    // ones idle workers are about to pick up.
+    pub(crate) fn try_push(&self, job: Job, priority: Priority) -> Result<(), Job> {
+        if let Some(capacity) = self.capacity {
+            let admitted = self
+                .queued
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                    (queued < capacity + self.idle.load(Ordering::SeqCst)).then_some(queued + 1)
+                })
+                .is_ok();
+            if !admitted {
+                return Err(job);
+            }
+        } else {
+            self.queued.fetch_add(1, Ordering::SeqCst);
+        }
+        let job = Queued { job, queued_at: Instant::now() };
+        match priority {
+            Priority::High => {
+                self.urgent.push(job);
+                self.wake_one();
+            }
+            Priority::Normal => self.push(job),
+        }
+        Ok(())
+    }
+
+    // Waits until there's room for the job.
+    pub(crate) fn push_blocking(&self, job: Job, priority: Priority) {
+        // Only take the lock when we actually have to wait.
+        let mut job = match self.try_push(job, priority) {
+            Ok(()) => return,
+            Err(job) => job,
+        };
+        let mut guard = self.space_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            job = match self.try_push(job, priority) {
+                Ok(()) => return,
+                Err(job) => job,
+            };
+            guard = self
+                .space
+                .wait_timeout(guard, PARK_TIMEOUT)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    // Takes the job that has been waiting the longest out of the queue, if there is one. High
+    // priority jobs are only dropped once there are no normal ones left.
+    pub(crate) fn pop_oldest(&self) -> Option<Job> {
+        let job = retry(|| self.injector.steal())
+            .success()
+            .or_else(|| {
+                let stealers = self.stealers.read().unwrap_or_else(PoisonError::into_inner);
+                stealers.iter().find_map(|stealer| retry(|| stealer.steal()).success())
+            })
+            .or_else(|| retry(|| self.urgent.steal()).success())?;
+        self.took_job();
+        Some(job.job)
+    }
+
+    // Blocks until there's a job for this worker, the queue is closed and empty, or the worker
+    // has had nothing to do for `idle_timeout`.
+    pub(crate) fn next_job(&self, idle_timeout: Option<Duration>) -> Next {
+        let mut searching = false;
+        let idle_since = Instant::now();
+        loop {
+            if let Some(job) = self.find_job() {
+                if searching && self.searching.fetch_sub(1, Ordering::SeqCst) == 1 && self.has_jobs() {
+                    self.wake_one();
+                }
+                self.took_job();
+                return Next::Job(job);
+            }
+            if searching {
+                self.searching.fetch_sub(1, Ordering::SeqCst);
+                searching = false;
+            }
+            if idle_timeout.is_some_and(|timeout| idle_since.elapsed() >= timeout) {
+                return Next::Idle;
+            }
+
+            let mut signals = self.sleep.lock().unwrap_or_else(PoisonError::into_inner);
+            self.idle.fetch_add(1, Ordering::SeqCst);
+            // Check again now that we're counted as idle: anyone pushing after this point sees
+            // `idle > 0` and wakes us up (or leaves the job to a worker that's searching
+            // already), anyone who pushed before we see here.
+            if !self.has_jobs() {
+                if self.closed.load(Ordering::SeqCst) {
+                    self.idle.fetch_sub(1, Ordering::SeqCst);
+                    return Next::Closed;
+                }
+                signals = self
+                    .wake
+                    .wait_timeout_while(signals, PARK_TIMEOUT, |signals| {
+                        *signals == 0 && !self.closed.load(Ordering::SeqCst)
+                    })
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+            // Whoever woke a sleeper already took one off `idle` and counted it as searching.
+            // If there's a wake-up waiting we take it, even if it was meant for someone else:
+            // we're awake and will do the searching, the other sleeper stays counted as idle.
+            if *signals > 0 {
+                *signals -= 1;
+                searching = true;
+            } else {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Workers finish what's queued and then `next_job` returns `Next::Closed`.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _guard = self.sleep.lock().unwrap_or_else(PoisonError::into_inner);
+        self.wake.notify_all();
+    }
+
+    fn push(&self, job: Queued) {
+        let address = self.address();
+        // A job running on one of our workers pushes onto that worker's deque, everybody else
+        // goes through the injector.
+        let job = LOCAL.with(|local| match &*local.borrow() {
+            Some((queue, deque)) if *queue == address => {
+                deque.push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            self.injector.push(job);
+        }
+        self.wake_one();
+    }
+
+    // Wakes up a sleeping worker, unless nobody is asleep or somebody is already searching.
+    fn wake_one(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.idle.load(Ordering::SeqCst) == 0 || self.searching.load(Ordering::SeqCst) > 0 {
+            return;
+        }
+        let mut signals = self.sleep.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.idle.load(Ordering::SeqCst) > 0 {
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+            self.searching.fetch_add(1, Ordering::SeqCst);
+            *signals += 1;
+            self.wake.notify_one();
+        }
+    }
+
+    fn find_job(&self) -> Option<Queued> {
+        LOCAL.with(|local| {
+            let local = local.borrow();
+            let (_, deque) = local.as_ref().expect("worker thread not attached to the queue");
+            // One at a time: a batch would land in our deque behind the normal jobs.
+            retry(|| self.urgent.steal()).success().or_else(|| deque.pop()).or_else(|| {
+                iter::repeat_with(|| {
+                    self.injector.steal_batch_and_pop(deque).or_else(|| {
+                        let stealers = self.stealers.read().unwrap_or_else(PoisonError::into_inner);
+                        stealers.iter().map(|stealer| stealer.steal()).collect()
+                    })
+                })
+                .find(|steal| !steal.is_retry())
+                .and_then(|steal| steal.success())
+            })
+        })
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.urgent.is_empty()
+            || !self.injector.is_empty()
+            || self
+                .stealers
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .any(|stealer| !stealer.is_empty())
+    }
+
+    fn took_job(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        if self.capacity.is_some() {
+            let _guard = self.space_lock.lock().unwrap_or_else(PoisonError::into_inner);
+            self.space.notify_one();
+        }
+    }
+
+    fn address(&self) -> usize {
+        self as *const JobQueue as usize
+    }
+}
+
+// Stealing can fail spuriously when it races with another thief, that just means "try again".
+fn retry<T>(mut steal: impl FnMut() -> Steal<T>) -> Steal<T> {
+    loop {
+        match steal() {
+            Steal::Retry => continue,
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, TryLockError};
+use std::time::{Duration, Instant};
+
+use crate::{Job, Priority, Shared};
+
+// What `ThreadPool::execute_after` and `ThreadPool::execute_every` give back. Dropping it does
+// *not* cancel anything (same as dropping a `thread::JoinHandle` doesn't stop the thread), you
+// have to call `cancel`.
+#[derive(Clone)]
+pub struct ScheduleHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ScheduleHandle {
+    // The job won't run anymore, unless a worker already started it. A periodic job that is
+    // running right now finishes that run and then stops.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// The jobs that are waiting for their time, sorted by when that is. One "timer" thread per pool
+// sleeps until the earliest deadline and then hands the job to the workers, so the workers never
+// block on a sleeping job.
+pub(crate) struct Timer {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+
+struct TimerState {
+    entries: BinaryHeap<Entry>,
+    // Breaks ties between entries with the same deadline, so they run in the order they were
+    // scheduled.
+    next_seq: u64,
+    stopped: bool,
+}
+
+struct Entry {
+    deadline: Instant,
+    seq: u64,
+    cancelled: Arc<AtomicBool>,
+    action: Action,
+}
+
+enum Action {
+    Once(Job),
+    // The closure is behind a `Mutex` so a run that takes longer than the period doesn't
+    // overlap with the next one, the next one is skipped instead.
+    Every(Duration, Arc<Mutex<dyn FnMut() + Send>>),
+}
+
+// `BinaryHeap` is a max-heap, so the entry with the *earliest* deadline has to compare biggest.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> CmpOrdering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl Timer {
+    pub(crate) fn new() -> Timer {
+        Timer {
+            state: Mutex::new(TimerState { entries: BinaryHeap::new(), next_seq: 0, stopped: false }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn once(&self, delay: Duration, job: Job) -> ScheduleHandle {
+        self.schedule(Instant::now() + delay, Action::Once(job))
+    }
+
+    pub(crate) fn every(&self, period: Duration, f: impl FnMut() + Send + 'static) -> ScheduleHandle {
+        self.schedule(Instant::now() + period, Action::Every(period, Arc::new(Mutex::new(f))))
+    }
+
+    fn schedule(&self, deadline: Instant, action: Action) -> ScheduleHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut state = self.lock();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry { deadline, seq, cancelled: Arc::clone(&cancelled), action });
+        // The new entry might be due before the one the timer thread is sleeping for.
+        self.changed.notify_one();
+        ScheduleHandle { cancelled }
+    }
+
+    // Jobs that aren't due yet are dropped without running.
+    pub(crate) fn stop(&self) {
+        let mut state = self.lock();
+        state.stopped = true;
+        state.entries.clear();
+        self.changed.notify_one();
+    }
+
+    // The timer thread's loop, returns after `stop`.
+    pub(crate) fn run(&self, shared: &Arc<Shared>) {
+        let mut state = self.lock();
+        loop {
+            if state.stopped {
+                return;
+            }
+            let now = Instant::now();
+            let deadline = state.entries.peek().map(|entry| entry.deadline);
+            match deadline {
+                None => state = self.changed.wait(state).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) if deadline > now => {
+                    state = self
+                        .changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+                Some(_) => {
+                    let entry = state.entries.pop().unwrap();
+                    if entry.cancelled.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    // Submitting can take the workers' locks, so not while holding ours.
+                    drop(state);
+                    let again = fire(entry, now, shared);
+                    state = self.lock();
+                    if let Some(entry) = again {
+                        state.entries.push(entry);
+                    }
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TimerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Hands the job to the workers. For a periodic job, returns the entry for its next run.
+fn fire(entry: Entry, now: Instant, shared: &Arc<Shared>) -> Option<Entry> {
+    let Entry { deadline, seq, cancelled, action } = entry;
+    let (job, again): (Job, _) = match action {
+        Action::Once(job) => {
+            let cancelled = Arc::clone(&cancelled);
+            let job = Box::new(move || {
+                // It might have been cancelled while it was waiting in the queue.
+                if !cancelled.load(Ordering::SeqCst) {
+                    job();
+                }
+            });
+            (job, None)
+        }
+        Action::Every(period, f) => {
+            let job = {
+                let f = Arc::clone(&f);
+                let cancelled = Arc::clone(&cancelled);
+                Box::new(move || {
+                    if cancelled.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    // A previous run panicked while holding the lock: that one is counted
+                    // already, we just keep going.
+                    let mut f = match f.try_lock() {
+                        Ok(f) => f,
+                        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                        // The previous run is still going.
+                        Err(TryLockError::WouldBlock) => return,
+                    };
+                    f();
+                })
+            };
+            // We count from when the run was due, not from when it ran, so the period doesn't
+            // drift. If we fell behind by more than a period we skip ahead instead of catching up
+            // with a burst of runs.
+            let next = if deadline + period > now { deadline + period } else { now + period };
+            (job, Some(Entry { deadline: next, seq, cancelled, action: Action::Every(period, f) }))
+        }
+    };
+    // The timer thread must never block on a full queue, a job that doesn't fit is dropped and
+    // counted like any other rejected job.
+    shared.grow_if_backed_up();
+    if shared.queue.try_push(job, Priority::Normal).is_err() {
+        shared.job_rejected();
+    }
+    again
+}
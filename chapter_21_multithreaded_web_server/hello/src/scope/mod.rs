@@ -0,0 +1,135 @@
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use crate::{ExecuteError, Job, Priority, Shared, ThreadPool};
+
+// Handed to the closure passed to `ThreadPool::scope`. Works like `std::thread::Scope`: jobs
+// executed through it may borrow anything that outlives the `scope` call ('env), because `scope`
+// doesn't return before every one of them has finished.
+// The two PhantomData markers make both lifetimes invariant, same as in std, so the compiler
+// can't shrink or stretch them to make a borrow fit that shouldn't.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    // Jobs that were executed through the scope and haven't finished (or been dropped) yet.
+    pending: Mutex<usize>,
+    all_done: Condvar,
+    a_job_panicked: AtomicBool,
+}
+
+impl ScopeState {
+    fn finish_one(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        *pending -= 1;
+        if *pending == 0 {
+            self.all_done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let _pending = self
+            .all_done
+            .wait_while(pending, |pending| *pending > 0)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+// A job together with the scope it belongs to. Whether it runs or gets dropped without running
+// (e.g. rejected by a full queue), the closure is gone before the scope hears about it, so
+// `scope` never returns while something that borrows from the caller is still alive.
+struct ScopedJob<F> {
+    f: Option<F>,
+    state: Arc<ScopeState>,
+}
+
+impl<F: FnOnce()> ScopedJob<F> {
+    fn run(mut self, shared: &Shared) {
+        let f = self.f.take().unwrap();
+        // The panic is reported to the scope, so the worker never sees it and we count it here.
+        if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+            shared.job_panicked();
+            self.state.a_job_panicked.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<F> Drop for ScopedJob<F> {
+    fn drop(&mut self) {
+        drop(self.f.take());
+        self.state.finish_one();
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    // Same as `ThreadPool::execute`, but `f` only has to live as long as the scope.
+    pub fn execute<F>(&'scope self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        let scoped = ScopedJob { f: Some(f), state: Arc::clone(&self.state) };
+        // Our own `Arc`, not a reference into the pool: once the scope hears the job is done
+        // the caller may move or drop the pool while this closure is still returning.
+        let shared = Arc::clone(&self.pool.shared);
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || scoped.run(&shared));
+        // SAFETY: The pool's queue only takes `'static` jobs, but this one can't outlive
+        // 'scope: `ThreadPool::scope` waits until `pending` is back to 0, and that only happens
+        // when the `ScopedJob` inside was dropped, after running or without running.
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.pool.execute_job(job, Priority::Normal)
+    }
+}
+
+impl ThreadPool {
+    // Runs `f` with a `Scope` whose jobs may borrow from the caller's stack, like
+    // `std::thread::scope` but on this pool's workers instead of new threads:
+    //
+    // let mut counts = [0; 4];
+    // pool.scope(|s| {
+    //     for count in &mut counts {
+    //         s.execute(move || *count += 1).unwrap();
+    //     }
+    // });
+    //
+    // It returns once every job executed through the scope has finished. If one of them
+    // panicked, `scope` panics too (after waiting for the others).
+    // NOTE: Calling `scope` from a job on the same pool blocks that worker while it waits, with
+    // every worker doing that nothing is left to run the scoped jobs.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                all_done: Condvar::new(),
+                a_job_panicked: AtomicBool::new(false),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // Even if `f` panics halfway through, jobs it already executed may still be using its
+        // borrows, so we wait for them before unwinding any further.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.wait();
+
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.a_job_panicked.load(Ordering::SeqCst) => {
+                panic!("a scoped job panicked")
+            }
+            Ok(result) => result,
+        }
+    }
+}
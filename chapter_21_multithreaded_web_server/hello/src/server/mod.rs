@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::http::{OnUpgrade, StatusCode};
+
+// A cloneable handle used to stop the server. `listener.incoming()` blocks until someone
+// connects, so flipping a flag alone is not enough: `trigger` also opens a throwaway connection
+// to our own listener to wake the accept loop up so it can notice the flag and break.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    listener: Listener,
+}
+
+// Where to connect to reach our listener.
+#[derive(Clone)]
+enum Listener {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Shutdown {
+    pub fn new(listener: &TcpListener) -> io::Result<Shutdown> {
+        let mut addr = listener.local_addr()?;
+        // We can't connect to 0.0.0.0 / :: everywhere, the loopback address reaches the same
+        // listener.
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        Ok(Shutdown { requested: Arc::new(AtomicBool::new(false)), listener: Listener::Tcp(addr) })
+    }
+
+    // For a listener on the Unix domain socket at `path`.
+    #[cfg(unix)]
+    pub fn unix(path: &Path) -> Shutdown {
+        Shutdown { requested: Arc::new(AtomicBool::new(false)), listener: Listener::Unix(path.to_path_buf()) }
+    }
+
+    pub fn trigger(&self) {
+        if !self.requested.swap(true, Ordering::SeqCst) {
+            // If this fails the listener is already gone, which is what we want anyway.
+            match &self.listener {
+                Listener::Tcp(addr) => drop(TcpStream::connect(addr)),
+                #[cfg(unix)]
+                Listener::Unix(path) => drop(UnixStream::connect(path)),
+            }
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+// Caps on open connections, checked as they're accepted: `max_total` for the whole server and
+// `max_per_ip` for any one client. The pool's queue limit alone doesn't stop one client from
+// opening connections until every worker is sitting on one of its keep-alives. `None` is no
+// cap. Connections over a Unix domain socket have no IP, only `max_total` applies to them.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    open: Arc<Mutex<OpenConnections>>,
+}
+
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    // Only addresses with a connection open, so this can't grow past `total` entries.
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// One open connection, counted until this is dropped.
+pub struct ConnectionPermit {
+    limits: ConnectionLimits,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionLimits {
+    pub fn new(max_total: Option<usize>, max_per_ip: Option<usize>) -> ConnectionLimits {
+        ConnectionLimits { max_total, max_per_ip, open: Arc::default() }
+    }
+
+    // Counts a new connection from `ip`, or says what to answer it with: 429 when that client
+    // has too many open, 503 when the whole server does.
+    pub fn acquire(&self, ip: Option<IpAddr>) -> Result<ConnectionPermit, StatusCode> {
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.max_total.is_some_and(|max| open.total >= max) {
+            return Err(StatusCode::ServiceUnavailable);
+        }
+        if let Some(ip) = ip {
+            let from_ip = open.per_ip.get(&ip).copied().unwrap_or(0);
+            if self.max_per_ip.is_some_and(|max| from_ip >= max) {
+                return Err(StatusCode::TooManyRequests);
+            }
+            open.per_ip.insert(ip, from_ip + 1);
+        }
+        open.total += 1;
+        Ok(ConnectionPermit { limits: self.clone(), ip })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap_or_else(PoisonError::into_inner);
+        open.total -= 1;
+        if let Some(ip) = self.ip
+            && let Some(from_ip) = open.per_ip.get_mut(&ip)
+        {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                open.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+// What the server needs from a socket besides reading and writing, so the same code serves TCP
+// and Unix domain sockets (`--unix`).
+pub trait Socket: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    // Only TCP has Nagle's algorithm to turn off, see `handle_connection` in `main`.
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The client's address. A Unix socket's clients are processes on this machine, they don't
+    // have one.
+    fn remote_addr(&self) -> Option<SocketAddr>;
+
+    // A second handle to the same socket.
+    fn try_clone(&self) -> io::Result<Self>
+    where
+        Self: Sized;
+}
+
+impl Socket for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl Socket for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+}
+
+// Reads from a socket with two limits: no single read waits longer than `timeout`, and once
+// there's a `deadline`, no read goes past it. A socket read timeout alone doesn't stop a slowloris
+// client, it can send one byte just before every timeout and keep a worker busy forever. The
+// deadline covers the whole request however the client spreads it out.
+// Put it inside the connection's `BufReader` and change the limits through `get_mut`. Writes go
+// straight to the socket.
+pub struct TimedStream {
+    stream: Box<dyn Socket + Send>,
+    timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl TimedStream {
+    pub fn new(stream: impl Socket + Send + 'static, timeout: Duration) -> TimedStream {
+        TimedStream { stream: Box::new(stream), timeout, deadline: None }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    pub fn get_ref(&self) -> &dyn Socket {
+        &*self.stream
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
+                }
+                left.min(self.timeout)
+            }
+            None => self.timeout,
+        };
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TimedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// What a connection's requests are read from and its responses written to: the `TimedStream`
+// itself for plain HTTP, or a TLS session on top of one. The server only needs `Read + Write`,
+// everything here is optional: any other stream (an in-memory one in a test, say) can be served
+// with an empty `impl Connection`, it just gets no timeouts.
+pub trait Connection: Read + Write {
+    // The socket underneath, to change its limits.
+    fn timed_stream(&mut self) -> Option<&mut TimedStream> {
+        None
+    }
+
+    // Called once we're done with the connection, before the socket is closed.
+    fn close(&mut self) {}
+}
+
+impl Connection for TimedStream {
+    fn timed_stream(&mut self) -> Option<&mut TimedStream> {
+        Some(self)
+    }
+}
+
+// A connection the HTTP code is done with, after a `101 Switching Protocols` answer. Whatever the
+// client sent right after its request is still in `buffered` (it may have been read into the
+// `BufReader` along with the request), so reads start there.
+pub struct Upgraded {
+    buffered: io::Cursor<Vec<u8>>,
+    connection: Box<dyn Connection + Send>,
+    // The connection still counts against `ConnectionLimits` while it's upgraded.
+    permit: Option<ConnectionPermit>,
+}
+
+impl Upgraded {
+    pub fn new(buffered: Vec<u8>, connection: Box<dyn Connection + Send>) -> Upgraded {
+        Upgraded { buffered: io::Cursor::new(buffered), connection, permit: None }
+    }
+
+    // How long a read may wait for the client. Upgraded connections tend to sit idle for
+    // long stretches, so this is usually much more than the HTTP read timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        if let Some(stream) = self.connection.timed_stream() {
+            stream.set_timeout(timeout);
+            stream.set_deadline(None);
+        }
+    }
+
+    pub fn remote_addr(&mut self) -> Option<SocketAddr> {
+        self.connection.timed_stream()?.get_ref().remote_addr()
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.buffered.read(buf)? {
+            0 => self.connection.read(buf),
+            read => Ok(read),
+        }
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.connection.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.flush()
+    }
+}
+
+impl Drop for Upgraded {
+    fn drop(&mut self) {
+        self.connection.close();
+    }
+}
+
+// How long an upgraded connection (a WebSocket) may go without sending us anything.
+pub const UPGRADED_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Hands a connection we sent a `101 Switching Protocols` on over to `upgrade`. That may keep it
+// for hours, so it gets a thread of its own instead of holding on to a pool worker (or a tokio
+// worker). `buffered` is what we already read from the connection past the request. `permit`
+// goes along with it, so the connection is counted until `upgrade` is done with it.
+pub fn hand_over(
+    buffered: Vec<u8>,
+    connection: Box<dyn Connection + Send>,
+    permit: ConnectionPermit,
+    upgrade: OnUpgrade,
+) {
+    let mut upgraded = Upgraded::new(buffered, connection);
+    upgraded.permit = Some(permit);
+    upgraded.set_timeout(UPGRADED_IDLE_TIMEOUT);
+    let spawned = thread::Builder::new()
+        .name(String::from("hello-upgraded"))
+        .spawn(move || upgrade(upgraded));
+    if let Err(e) = spawned {
+        eprintln!("Failed to start a thread for an upgraded connection: {e}");
+    }
+}
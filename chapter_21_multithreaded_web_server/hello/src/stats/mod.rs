@@ -0,0 +1,158 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Something that wants to know what the pool is doing, e.g. to log it or feed it into a metrics
+// system. Set it with `ThreadPoolBuilder::observer`. The pool itself doesn't print anything.
+// `on_event` is called on whatever thread the event happened on (a worker, the supervisor, the
+// thread dropping the pool...), so it should be quick and must not block.
+// Any `Fn(&PoolEvent)` closure works as an observer:
+// ThreadPool::builder().observer(|event: &PoolEvent| eprintln!("{event:?}")).build()?
+pub trait PoolObserver: Send + Sync {
+    fn on_event(&self, event: &PoolEvent);
+}
+
+impl<F> PoolObserver for F
+where
+    F: Fn(&PoolEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &PoolEvent) {
+        self(event)
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolEvent {
+    WorkerStarted { id: usize },
+    // The worker had nothing to do for `idle` and the pool had more than `min_threads`.
+    WorkerRetired { id: usize, idle: Duration },
+    // The worker's thread died and the supervisor put a new one in its place.
+    WorkerRespawned { id: usize },
+    // The OS wouldn't give us a thread. `id` is the worker we tried to start or replace.
+    SpawnFailed { id: usize, error: io::Error },
+    // The pool is shutting down and the worker finished everything that was queued.
+    WorkerStopped { id: usize },
+    // `shutdown_timeout` gave up waiting for the worker.
+    WorkerDetached { id: usize },
+    // A job ran, `waited` is how long it sat in the queue and `ran` how long it took.
+    JobFinished { waited: Duration, ran: Duration },
+    JobPanicked,
+    // A full queue turned a job away or threw it out.
+    JobRejected,
+}
+
+// A snapshot of the pool's counters, from `ThreadPool::stats`. The numbers are read one after
+// the other while the pool keeps running, so they don't have to add up exactly.
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    // Worker threads the pool has right now.
+    pub threads: usize,
+    // Workers that are running a job right now.
+    pub active_workers: usize,
+    // Jobs waiting to be started.
+    pub queued_jobs: usize,
+    // Jobs that ran, including the ones that panicked.
+    pub completed_jobs: u64,
+    pub panicked_jobs: usize,
+    pub rejected_jobs: usize,
+    pub respawned_workers: usize,
+    // How long jobs waited in the queue before a worker started them.
+    pub queue_wait: Histogram,
+    // How long jobs took once they were started.
+    pub run_time: Histogram,
+}
+
+// Bucket `i` counts the durations below 2^i microseconds (and at least 2^(i - 1)), the last one
+// everything longer: 1us, 2us, 4us, ... up to about 16s.
+const BUCKETS: usize = 25;
+
+// A latency histogram with power-of-two buckets. Coarse, but recording is one atomic add, which
+// matters when every job records twice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl Histogram {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    // The duration below which `percentile` percent of the recorded durations are, rounded up to
+    // the bucket's upper bound. `None` if nothing was recorded yet.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&n| {
+                seen += n;
+                seen >= rank
+            })
+            .unwrap_or(BUCKETS - 1);
+        Some(upper_bound(bucket))
+    }
+
+    // Every non-empty bucket as (upper bound, count). The last bucket's bound is `Duration::MAX`.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n > 0)
+            .map(|(bucket, n)| (upper_bound(bucket), *n))
+    }
+}
+
+fn upper_bound(bucket: usize) -> Duration {
+    if bucket == BUCKETS - 1 {
+        Duration::MAX
+    } else {
+        Duration::from_micros(1 << bucket)
+    }
+}
+
+// The pool's side of a `Histogram`.
+pub(crate) struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub(crate) fn new() -> LatencyHistogram {
+        LatencyHistogram { buckets: std::array::from_fn(|_| AtomicU64::new(0)) }
+    }
+
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        // The number of bits `micros` needs is the bucket: 0 -> 0, 1 -> 1, 2..=3 -> 2, ...
+        let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Histogram {
+        Histogram { buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentiles_round_up_to_buckets() {
+        let histogram = LatencyHistogram::new();
+        for micros in [0, 3, 3, 100] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 4);
+        assert_eq!(snapshot.percentile(25.0), Some(Duration::from_micros(1)));
+        assert_eq!(snapshot.percentile(50.0), Some(Duration::from_micros(4)));
+        assert_eq!(snapshot.percentile(100.0), Some(Duration::from_micros(128)));
+        assert_eq!(LatencyHistogram::new().snapshot().percentile(50.0), None);
+    }
+}
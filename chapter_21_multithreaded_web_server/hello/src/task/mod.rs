@@ -0,0 +1,160 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::ExecuteError;
+
+// What `ThreadPool::spawn` gives back: a way to get at the closure's return value once a worker
+// has run it. Like `thread::JoinHandle` you can block on it with `join`, but you can also wait
+// with a timeout or `.await` it, since it implements `Future`.
+pub struct TaskHandle<T> {
+    state: Arc<TaskState<T>>,
+}
+
+// The worker's side of the handle. Whoever ends up owning it (normally the job closure) must
+// either `complete` it or drop it, in which case the task counts as cancelled.
+pub(crate) struct Completer<T> {
+    state: Option<Arc<TaskState<T>>>,
+}
+
+struct TaskState<T> {
+    slot: Mutex<Slot<T>>,
+    finished: Condvar,
+}
+
+enum Slot<T> {
+    Running(Option<Waker>),
+    Done(Result<T, TaskError>),
+    Taken,
+}
+
+#[derive(Debug)]
+pub enum TaskError {
+    // The closure panicked. This is the panic payload, just like `thread::JoinHandle::join`
+    // gives you.
+    Panicked(Box<dyn Any + Send + 'static>),
+    // The pool's queue was full and turned the job down.
+    Rejected(ExecuteError),
+    // The job was dropped without ever running, e.g. by `RejectionPolicy::DropOldest`.
+    Cancelled,
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TaskError::Panicked(payload) => {
+                // `panic!` payloads are a `&str` or a `String`, anything else we can't print.
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("Box<dyn Any>");
+                write!(f, "task panicked: {message}")
+            }
+            TaskError::Rejected(e) => write!(f, "task was rejected: {e}"),
+            TaskError::Cancelled => write!(f, "task was dropped before it ran"),
+        }
+    }
+}
+
+impl Error for TaskError {}
+
+pub(crate) fn task<T>() -> (TaskHandle<T>, Completer<T>) {
+    let state = Arc::new(TaskState {
+        slot: Mutex::new(Slot::Running(None)),
+        finished: Condvar::new(),
+    });
+    let handle = TaskHandle { state: Arc::clone(&state) };
+    (handle, Completer { state: Some(state) })
+}
+
+impl<T> TaskState<T> {
+    fn finish(&self, result: Result<T, TaskError>) {
+        let mut slot = self.slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Slot::Running(Some(waker)) = std::mem::replace(&mut *slot, Slot::Done(result)) {
+            waker.wake();
+        }
+        self.finished.notify_all();
+    }
+}
+
+impl<T> Completer<T> {
+    pub(crate) fn complete(mut self, result: Result<T, TaskError>) {
+        if let Some(state) = self.state.take() {
+            state.finish(result);
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.finish(Err(TaskError::Cancelled));
+        }
+    }
+}
+
+impl<T> TaskHandle<T> {
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.lock(), Slot::Running(_))
+    }
+
+    // Blocks for at most `timeout`. Returns `true` if the task is done, then `join` won't block.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let slot = self.lock();
+        let (slot, _) = self
+            .state
+            .finished
+            .wait_timeout_while(slot, timeout, |slot| matches!(slot, Slot::Running(_)))
+            .unwrap_or_else(PoisonError::into_inner);
+        !matches!(*slot, Slot::Running(_))
+    }
+
+    // Blocks until the task is done and returns what the closure returned.
+    pub fn join(self) -> Result<T, TaskError> {
+        let slot = self.lock();
+        let mut slot = self
+            .state
+            .finished
+            .wait_while(slot, |slot| matches!(slot, Slot::Running(_)))
+            .unwrap_or_else(PoisonError::into_inner);
+        match std::mem::replace(&mut *slot, Slot::Taken) {
+            Slot::Done(result) => result,
+            // `join` takes `self` and `poll` never hands out a result it didn't take from
+            // `Done`, so `Taken` means the future was polled to completion first.
+            _ => panic!("TaskHandle joined after its future completed"),
+        }
+    }
+
+    // The job never made it into the pool. Its `Completer` was dropped together with it and
+    // marked the task as cancelled, we know better.
+    pub(crate) fn reject(&self, e: ExecuteError) {
+        *self.lock() = Slot::Done(Err(TaskError::Rejected(e)));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slot<T>> {
+        self.state.slot.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Future for TaskHandle<T> {
+    type Output = Result<T, TaskError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.lock();
+        match std::mem::replace(&mut *slot, Slot::Taken) {
+            Slot::Done(result) => Poll::Ready(result),
+            // Remember the latest waker, the worker wakes it when it's done.
+            Slot::Running(_) => {
+                *slot = Slot::Running(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+            Slot::Taken => panic!("TaskHandle polled after completion"),
+        }
+    }
+}
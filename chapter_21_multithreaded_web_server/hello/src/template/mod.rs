@@ -0,0 +1,530 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+// A small template engine, so pages can show data instead of being served as they are on disk:
+//
+// <h1>Hello, {{ user.name }}!</h1>
+// {% include "nav.html" %}
+// {% if todos %}
+//   <ul>
+//   {% for todo in todos %}
+//     <li>{{ loop.index }}. {{ todo.title }}</li>
+//   {% endfor %}
+//   </ul>
+// {% else %}
+//   <p>Nothing to do.</p>
+// {% endif %}
+// {# comments don't end up in the page #}
+//
+// `{{ ... }}` is HTML-escaped, `{{ html | raw }}` isn't. A variable that doesn't exist is an
+// error when printed, and false in an `if` (`{% if not user %}` works too). Inside a `for`,
+// `loop.index` counts from 1, `loop.first` and `loop.last` say where we are. A tag alone on its
+// line takes the whole line with it, so the output isn't full of blank lines.
+//
+// `Templates` loads them from a directory, parses each once and keeps it. With `reload` (for
+// development) it checks the file's modification time on every render and parses it again
+// when it changed, so edits show up without restarting the server.
+
+// The data a template is rendered with. The context is a `Map`:
+//
+// let todos = Value::from(vec!["eat", "sleep"]);
+// let context: Value = [("name", Value::from("Ferris")), ("todos", todos)].into_iter().collect();
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Value {
+    #[default]
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    // What `if` goes by: nothing, false, 0 and empty things are false.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(value) => *value,
+            Value::Number(number) => *number != 0.0,
+            Value::String(string) => !string.is_empty(),
+            Value::List(list) => !list.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.get(key),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::String(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Number(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value {
+        Value::Number(value as f64)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Value {
+        Value::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>> FromIterator<(K, V)> for Value {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> Value {
+        Value::Map(entries.into_iter().map(|(key, value)| (key.into(), value.into())).collect())
+    }
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io { name: String, error: io::Error },
+    // The template itself is wrong, found while parsing.
+    Syntax { name: String, line: usize, message: String },
+    // The template is fine but doesn't fit the data, like a variable that isn't there.
+    Render { name: String, line: usize, message: String },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::Io { name, error } => write!(f, "can't read template {name}: {error}"),
+            TemplateError::Syntax { name, line, message } => write!(f, "{name}:{line}: {message}"),
+            TemplateError::Render { name, line, message } => write!(f, "{name}:{line}: {message}"),
+        }
+    }
+}
+
+impl Error for TemplateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TemplateError::Io { error, .. } => Some(error),
+            TemplateError::Syntax { .. } | TemplateError::Render { .. } => None,
+        }
+    }
+}
+
+// A parsed template. It's rendered through `Templates`, that's where its includes come from.
+#[derive(Debug)]
+struct Template {
+    name: String,
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Print { path: Vec<String>, raw: bool, line: usize },
+    If { negated: bool, path: Vec<String>, then: Vec<Node>, otherwise: Vec<Node> },
+    For { variable: String, path: Vec<String>, body: Vec<Node>, line: usize },
+    Include { name: String, line: usize },
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    // What's between `{{ }}` or `{% %}`, trimmed.
+    Print(&'a str, usize),
+    Tag(&'a str, usize),
+}
+
+impl Template {
+    fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let syntax = |line, message: String| TemplateError::Syntax { name: name.to_string(), line, message };
+        let tokens = tokenize(source).map_err(|(line, message)| syntax(line, message))?;
+        let mut tokens = tokens.into_iter();
+        let (nodes, end) = parse_nodes(&mut tokens).map_err(|(line, message)| syntax(line, message))?;
+        if let Some((tag, line)) = end {
+            return Err(syntax(line, format!("{{% {tag} %}} without a matching opening tag")));
+        }
+        Ok(Template { name: name.to_string(), nodes })
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token<'_>>, (usize, String)> {
+    let mut tokens = Vec::new();
+    // Where the text we haven't made a token of yet starts.
+    let mut cursor = 0;
+    // A lone `{` is just text.
+    let opening = |from: usize| {
+        source[from..]
+            .match_indices('{')
+            .map(|(offset, _)| from + offset)
+            .find(|&start| matches!(source.as_bytes().get(start + 1), Some(b'{' | b'%' | b'#')))
+    };
+    while let Some(start) = opening(cursor) {
+        let close = match &source[start..start + 2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let line = 1 + source[..start].matches('\n').count();
+        let Some(length) = source[start + 2..].find(close) else {
+            return Err((line, format!("{} without {close}", &source[start..start + 2])));
+        };
+        let end = start + 2 + length + 2;
+        let inside = source[start + 2..end - 2].trim();
+
+        // A tag or comment on a line of its own: drop the indentation before it and the line
+        // break after it.
+        let line_start = source[..start].rfind('\n').map_or(0, |newline| newline + 1);
+        let line_end = source[end..].find('\n').map_or(source.len(), |newline| end + newline + 1);
+        let blank = |text: &str| text.trim_start_matches([' ', '\t']).trim_end_matches(['\r', '\n']).is_empty();
+        let alone = close != "}}" && line_start >= cursor && blank(&source[line_start..start]) && blank(&source[end..line_end]);
+        if alone {
+            tokens.push(Token::Text(&source[cursor..line_start]));
+            cursor = line_end;
+        } else {
+            tokens.push(Token::Text(&source[cursor..start]));
+            cursor = end;
+        }
+        match close {
+            "}}" => tokens.push(Token::Print(inside, line)),
+            "%}" => tokens.push(Token::Tag(inside, line)),
+            // Comments leave nothing behind.
+            _ => {}
+        }
+    }
+    tokens.push(Token::Text(&source[cursor..]));
+    tokens.retain(|token| !matches!(token, Token::Text("")));
+    Ok(tokens)
+}
+
+// Parses until the end of the template or a tag that closes a block (`else`, `endif`,
+// `endfor`), which is returned with its line for the caller to check.
+type Parsed = (Vec<Node>, Option<(String, usize)>);
+
+fn parse_nodes<'a>(tokens: &mut impl Iterator<Item = Token<'a>>) -> Result<Parsed, (usize, String)> {
+    let mut nodes = Vec::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+            Token::Print(inside, line) => {
+                let (expression, raw) = match inside.split_once('|') {
+                    Some((expression, filter)) if filter.trim() == "raw" => (expression, true),
+                    Some((_, filter)) => return Err((line, format!("unknown filter {:?}", filter.trim()))),
+                    None => (inside, false),
+                };
+                nodes.push(Node::Print { path: parse_path(expression.trim(), line)?, raw, line });
+            }
+            Token::Tag(inside, line) => {
+                let words: Vec<&str> = inside.split_whitespace().collect();
+                match words[..] {
+                    ["if", "not", path] | ["if", path] => {
+                        let (then, end) = parse_nodes(tokens)?;
+                        let otherwise = match end {
+                            Some((tag, _)) if tag == "endif" => Vec::new(),
+                            Some((tag, _)) if tag == "else" => match parse_nodes(tokens)? {
+                                (otherwise, Some((tag, _))) if tag == "endif" => otherwise,
+                                _ => return Err((line, String::from("{% if %} without {% endif %}"))),
+                            },
+                            _ => return Err((line, String::from("{% if %} without {% endif %}"))),
+                        };
+                        let negated = words[1] == "not";
+                        nodes.push(Node::If { negated, path: parse_path(path, line)?, then, otherwise });
+                    }
+                    ["for", variable, "in", path] => {
+                        let (body, end) = parse_nodes(tokens)?;
+                        if end.is_none_or(|(tag, _)| tag != "endfor") {
+                            return Err((line, String::from("{% for %} without {% endfor %}")));
+                        }
+                        let variable = parse_path(variable, line)?.join(".");
+                        if variable.contains('.') || variable == "loop" {
+                            return Err((line, format!("can't loop into {variable:?}")));
+                        }
+                        nodes.push(Node::For { variable, path: parse_path(path, line)?, body, line });
+                    }
+                    ["include", name] if name.len() >= 2 && name.starts_with('"') && name.ends_with('"') => {
+                        nodes.push(Node::Include { name: name[1..name.len() - 1].to_string(), line });
+                    }
+                    ["else"] | ["endif"] | ["endfor"] => return Ok((nodes, Some((words[0].to_string(), line)))),
+                    _ => return Err((line, format!("unknown tag {{% {inside} %}}"))),
+                }
+            }
+        }
+    }
+    Ok((nodes, None))
+}
+
+// "user.name" -> ["user", "name"]
+fn parse_path(expression: &str, line: usize) -> Result<Vec<String>, (usize, String)> {
+    let valid = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if expression.split('.').all(valid) {
+        Ok(expression.split('.').map(String::from).collect())
+    } else {
+        Err((line, format!("{expression:?} isn't a variable name")))
+    }
+}
+
+// Loaded templates, by name. Names are paths relative to the directory, and can't leave it.
+pub struct Templates {
+    dir: PathBuf,
+    reload: bool,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+struct Cached {
+    template: Arc<Template>,
+    // `None` for the ones `add`ed from a string, those never change.
+    modified: Option<SystemTime>,
+}
+
+// Includes including themselves would go on forever.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+impl Templates {
+    pub fn new(dir: impl Into<PathBuf>, reload: bool) -> Templates {
+        Templates { dir: dir.into(), reload, cache: Mutex::default() }
+    }
+
+    // A template that isn't a file, say one built into the binary. It replaces any file of the
+    // same name.
+    pub fn add(&self, name: &str, source: &str) -> Result<(), TemplateError> {
+        let template = Arc::new(Template::parse(name, source)?);
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.insert(name.to_string(), Cached { template, modified: None });
+        Ok(())
+    }
+
+    pub fn render(&self, name: &str, context: &Value) -> Result<String, TemplateError> {
+        let template = self.get(name)?;
+        let mut renderer = Renderer { templates: self, context, scopes: Vec::new(), out: String::new() };
+        renderer.render(&template, &template.nodes, 0)?;
+        Ok(renderer.out)
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let io_error = |error| TemplateError::Io { name: name.to_string(), error };
+        let cached = {
+            let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            cache.get(name).map(|cached| (Arc::clone(&cached.template), cached.modified))
+        };
+        let path = self.path(name).ok_or_else(|| io_error(io::Error::from(io::ErrorKind::InvalidInput)))?;
+        match cached {
+            Some((template, None)) => return Ok(template),
+            Some((template, Some(_))) if !self.reload => return Ok(template),
+            _ => {}
+        }
+
+        let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).map_err(io_error)?;
+        if let Some((template, Some(cached_modified))) = cached
+            && cached_modified == modified
+        {
+            return Ok(template);
+        }
+        // Parsing outside the lock, two threads may both do it after a change but that's harmless.
+        let source = fs::read_to_string(&path).map_err(io_error)?;
+        let template = Arc::new(Template::parse(name, &source)?);
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.insert(name.to_string(), Cached { template: Arc::clone(&template), modified: Some(modified) });
+        Ok(template)
+    }
+
+    // Like the static files, nothing outside `dir`: no "..", no absolute paths.
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        let relative = Path::new(name);
+        let inside = relative.components().all(|component| matches!(component, Component::Normal(_)));
+        inside.then(|| self.dir.join(relative))
+    }
+}
+
+struct Renderer<'a> {
+    templates: &'a Templates,
+    context: &'a Value,
+    // Loop variables, innermost last. They hide context entries of the same name.
+    scopes: Vec<(String, Value)>,
+    out: String,
+}
+
+impl Renderer<'_> {
+    fn render(&mut self, template: &Template, nodes: &[Node], depth: usize) -> Result<(), TemplateError> {
+        let error = |line, message: String| TemplateError::Render { name: template.name.clone(), line, message };
+        for node in nodes {
+            match node {
+                Node::Text(text) => self.out.push_str(text),
+                Node::Print { path, raw, line } => {
+                    let text = match self.lookup(path) {
+                        None => return Err(error(*line, format!("{} isn't defined", path.join(".")))),
+                        Some(Value::Null) => String::new(),
+                        Some(Value::Bool(value)) => value.to_string(),
+                        Some(Value::Number(number)) => number.to_string(),
+                        Some(Value::String(string)) => string.clone(),
+                        Some(Value::List(_) | Value::Map(_)) => {
+                            return Err(error(*line, format!("{} is a list or map, it can't be printed", path.join("."))));
+                        }
+                    };
+                    if *raw {
+                        self.out.push_str(&text);
+                    } else {
+                        escape_html(&text, &mut self.out);
+                    }
+                }
+                Node::If { negated, path, then, otherwise } => {
+                    let truthy = self.lookup(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negated { then } else { otherwise };
+                    self.render(template, branch, depth)?;
+                }
+                Node::For { variable, path, body, line } => {
+                    let items = match self.lookup(path) {
+                        Some(Value::List(items)) => items.clone(),
+                        // Nothing to loop over is no loop, like in `if`.
+                        None | Some(Value::Null) => Vec::new(),
+                        Some(_) => return Err(error(*line, format!("{} isn't a list", path.join(".")))),
+                    };
+                    let count = items.len();
+                    for (index, item) in items.into_iter().enumerate() {
+                        let meta: Value = [
+                            ("index", Value::from(index + 1)),
+                            ("first", Value::from(index == 0)),
+                            ("last", Value::from(index + 1 == count)),
+                        ]
+                        .into_iter()
+                        .collect();
+                        self.scopes.push((String::from("loop"), meta));
+                        self.scopes.push((variable.clone(), item));
+                        let rendered = self.render(template, body, depth);
+                        self.scopes.truncate(self.scopes.len() - 2);
+                        rendered?;
+                    }
+                }
+                Node::Include { name, line } => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(error(*line, format!("includes nested too deep including {name}, is it including itself?")));
+                    }
+                    let included = self.templates.get(name)?;
+                    self.render(&included, &included.nodes, depth + 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let scoped = self.scopes.iter().rev().find(|(name, _)| name == first).map(|(_, value)| value);
+        let mut value = scoped.or_else(|| self.context.get(first))?;
+        for key in rest {
+            value = value.get(key)?;
+        }
+        Some(value)
+    }
+}
+
+// The five characters that can change what HTML means. Everything a user sent us goes through
+// here before it ends up in a page.
+pub fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str, context: &Value) -> Result<String, TemplateError> {
+        let templates = Templates::new(".", false);
+        templates.add("page.html", source)?;
+        templates.render("page.html", context)
+    }
+
+    #[test]
+    fn renders_variables_loops_and_conditionals() {
+        let context: Value = [
+            ("name", Value::from("<Ferris & co>")),
+            ("todos", Value::from(vec!["eat", "sleep"])),
+            ("user", [("admin", false)].into_iter().collect()),
+        ]
+        .into_iter()
+        .collect();
+        let source = "\
+<h1>{{ name }}</h1>{{ name | raw }}
+<ul>
+  {% for todo in todos %}
+  <li>{{ loop.index }}. {{ todo }}{% if loop.last %}!{% endif %}</li>
+  {% endfor %}
+</ul>
+{% if not user.admin %}
+<p>Not an admin{# yet #}.</p>
+{% else %}
+<p>Admin.</p>
+{% endif %}
+";
+        let expected = "\
+<h1>&lt;Ferris &amp; co&gt;</h1><Ferris & co>
+<ul>
+  <li>1. eat</li>
+  <li>2. sleep!</li>
+</ul>
+<p>Not an admin.</p>
+";
+        assert_eq!(render(source, &context).unwrap(), expected);
+    }
+
+    #[test]
+    fn includes_and_errors() {
+        let templates = Templates::new(".", false);
+        templates.add("nav.html", "<nav>{{ title }}</nav>").unwrap();
+        templates.add("page.html", "{% include \"nav.html\" %}<main></main>").unwrap();
+        templates.add("loop.html", "{% include \"loop.html\" %}").unwrap();
+        let context: Value = [("title", "Home")].into_iter().collect();
+        assert_eq!(templates.render("page.html", &context).unwrap(), "<nav>Home</nav><main></main>");
+        assert!(matches!(templates.render("loop.html", &context), Err(TemplateError::Render { .. })));
+
+        assert!(matches!(render("{{ missing }}", &Value::Null), Err(TemplateError::Render { line: 1, .. })));
+        assert!(matches!(render("\n{% if x %}", &Value::Null), Err(TemplateError::Syntax { line: 2, .. })));
+        assert!(matches!(render("{{ a b }}", &Value::Null), Err(TemplateError::Syntax { .. })));
+        assert!(matches!(templates.render("../secret.html", &context), Err(TemplateError::Io { .. })));
+    }
+}
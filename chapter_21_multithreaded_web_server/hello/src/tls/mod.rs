@@ -0,0 +1,94 @@
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConnection, StreamOwned};
+
+use crate::server::{Connection, TimedStream};
+
+pub use rustls::ServerConfig;
+
+// HTTPS, only built with `--features tls`. The TLS session sits between the socket and the HTTP
+// code, which doesn't notice the difference:
+//
+// socket -> TimedStream (timeouts) -> TlsStream (decryption) -> BufReader -> Request
+//
+// The handshake isn't done up front, rustls does it on the first read, within the same timeouts
+// as any other read.
+pub type TlsStream = StreamOwned<ServerConnection, TimedStream>;
+
+#[derive(Debug)]
+pub enum TlsError {
+    // The file is missing, unreadable or not PEM.
+    Pem { path: PathBuf, error: pem::Error },
+    NoCertificates(PathBuf),
+    // The key doesn't belong to the certificate, or rustls doesn't support it.
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Pem { path, error } => write!(f, "failed to read {}: {error}", path.display()),
+            TlsError::NoCertificates(path) => write!(f, "no certificates in {}", path.display()),
+            TlsError::Rustls(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::Pem { error, .. } => Some(error),
+            TlsError::NoCertificates(_) => None,
+            TlsError::Rustls(e) => Some(e),
+        }
+    }
+}
+
+// Loads the certificate chain (ours first, then any intermediates) and its private key from PEM
+// files, the format Let's Encrypt and `openssl` hand out. Done once at startup, every connection
+// shares the result.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let pem_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |error| TlsError::Pem { path, error }
+    };
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(pem_error(cert_path))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(cert_path.to_path_buf()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(pem_error(key_path))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(TlsError::Rustls)?;
+    // We only speak HTTP/1.1, this tells clients that try to negotiate HTTP/2.
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+pub fn accept(config: &Arc<ServerConfig>, stream: TimedStream) -> Result<TlsStream, TlsError> {
+    let connection = ServerConnection::new(Arc::clone(config)).map_err(TlsError::Rustls)?;
+    Ok(StreamOwned::new(connection, stream))
+}
+
+impl Connection for TlsStream {
+    fn timed_stream(&mut self) -> Option<&mut TimedStream> {
+        Some(&mut self.sock)
+    }
+
+    // Without a close_notify the client can't tell our closing the connection from someone
+    // in between cutting it off.
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+    }
+}
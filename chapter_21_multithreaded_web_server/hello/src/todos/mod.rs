@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::handler::Handler;
+use crate::http::{Problem, Request, Response, StatusCode};
+
+// An example JSON API, the shape of service this server is meant for: a list of todos kept in
+// memory (gone when the server stops).
+//
+// GET    /todos         all of them
+// POST   /todos         {"title": "..."} adds one: 201 with a Location header
+// GET    /todos/{id}    one
+// PUT    /todos/{id}    {"title": "...", "completed": true} replaces one
+// PATCH  /todos/{id}    {"completed": true} changes only the fields that are there
+// DELETE /todos/{id}    removes one: 204
+//
+// Errors are problem documents (see `Problem`): 404 for an id we don't have, 405 for a method
+// the path doesn't support, 422 with a message per field when the todo isn't valid.
+#[derive(Default)]
+pub struct Todos {
+    store: Mutex<Store>,
+}
+
+#[derive(Default)]
+struct Store {
+    todos: BTreeMap<u64, Todo>,
+    // Ids aren't reused, so an old link never points at a different todo.
+    last_id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Todo {
+    pub id: u64,
+    pub title: String,
+    pub completed: bool,
+}
+
+// What a client sends. POST and PUT need `title`, only PATCH may leave every field out.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TodoInput {
+    title: Option<String>,
+    completed: Option<bool>,
+}
+
+const MAX_TITLE_CHARS: usize = 200;
+
+impl TodoInput {
+    // Problems with the fields' values, the types were checked while parsing.
+    fn validate(&self, title_required: bool) -> Result<(), Problem> {
+        let error = match &self.title {
+            None if title_required => String::from("is required"),
+            Some(title) if title.trim().is_empty() => String::from("can't be empty"),
+            Some(title) if title.chars().count() > MAX_TITLE_CHARS => {
+                format!("can't be longer than {MAX_TITLE_CHARS} characters")
+            }
+            _ => return Ok(()),
+        };
+        Err(Problem::new(StatusCode::UnprocessableContent, "the todo isn't valid").with_error("title", error))
+    }
+}
+
+impl Todos {
+    pub fn new() -> Todos {
+        Todos::default()
+    }
+
+    // Whether `path` is one of ours, for routing.
+    pub fn matches(path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or(path);
+        path == "/todos" || path.starts_with("/todos/")
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn list(&self) -> Response {
+        let todos: Vec<Todo> = self.store().todos.values().cloned().collect();
+        Response::json(StatusCode::Ok, &todos)
+    }
+
+    fn create(&self, request: &Request) -> Result<Response, Problem> {
+        let input: TodoInput = request.json()?;
+        input.validate(true)?;
+        let mut store = self.store();
+        store.last_id += 1;
+        let todo = Todo {
+            id: store.last_id,
+            title: input.title.unwrap_or_default().trim().to_string(),
+            completed: input.completed.unwrap_or(false),
+        };
+        store.todos.insert(todo.id, todo.clone());
+        Ok(Response::json(StatusCode::Created, &todo).with_header("Location", format!("/todos/{}", todo.id)))
+    }
+
+    // PUT replaces the whole todo (a missing `completed` is false), PATCH only what's given.
+    fn update(&self, id: u64, request: &Request, replace: bool) -> Result<Response, Problem> {
+        let input: TodoInput = request.json()?;
+        input.validate(replace)?;
+        let mut store = self.store();
+        let todo = store.todos.get_mut(&id).ok_or_else(|| not_found(id))?;
+        if let Some(title) = input.title {
+            todo.title = title.trim().to_string();
+        }
+        match input.completed {
+            Some(completed) => todo.completed = completed,
+            None if replace => todo.completed = false,
+            None => {}
+        }
+        Ok(Response::json(StatusCode::Ok, todo))
+    }
+}
+
+impl Handler for Todos {
+    fn handle(&self, request: &Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or("");
+        // "/todos" is the list, "/todos/3" is todo 3, anything else isn't ours.
+        let id = match path.strip_prefix("/todos") {
+            Some("" | "/") => None,
+            Some(rest) if let Some(Ok(id)) = rest.strip_prefix('/').map(str::parse) => Some(id),
+            _ => return Problem::new(StatusCode::NotFound, format!("there's nothing at {path}")).into(),
+        };
+
+        let result = match (request.method.as_str(), id) {
+            ("GET", None) => Ok(self.list()),
+            ("POST", None) => self.create(request),
+            ("GET", Some(id)) => match self.store().todos.get(&id) {
+                Some(todo) => Ok(Response::json(StatusCode::Ok, todo)),
+                None => Err(not_found(id)),
+            },
+            ("PUT", Some(id)) => self.update(id, request, true),
+            ("PATCH", Some(id)) => self.update(id, request, false),
+            ("DELETE", Some(id)) => match self.store().todos.remove(&id) {
+                Some(_) => Ok(Response::new(StatusCode::NoContent)),
+                None => Err(not_found(id)),
+            },
+            (method, id) => {
+                let allowed = if id.is_some() { "GET, PUT, PATCH, DELETE" } else { "GET, POST" };
+                let problem = Problem::new(StatusCode::MethodNotAllowed, format!("{method} isn't supported here"));
+                return Response::from(problem).with_header("Allow", allowed);
+            }
+        };
+        result.unwrap_or_else(Response::from)
+    }
+}
+
+fn not_found(id: u64) -> Problem {
+    Problem::new(StatusCode::NotFound, format!("there's no todo {id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, json: &str) -> Request {
+        let raw = format!(
+            "{method} {path} HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{json}",
+            json.len()
+        );
+        Request::read_from(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn validates_todos() {
+        let todos = Todos::new();
+        let problem = |response: Response| -> serde_json::Value {
+            assert_eq!(response.headers.get("Content-Type"), Some("application/problem+json"));
+            let crate::http::Body::Bytes(bytes) = response.body else { panic!("expected bytes") };
+            serde_json::from_slice(&bytes).unwrap()
+        };
+
+        let empty = problem(todos.handle(&request("POST", "/todos", r#"{"title": "  "}"#)));
+        assert_eq!(empty["status"], 422);
+        assert_eq!(empty["errors"]["title"], "can't be empty");
+        let missing = problem(todos.handle(&request("PUT", "/todos/1", r#"{"completed": true}"#)));
+        assert_eq!(missing["errors"]["title"], "is required");
+        let unknown = problem(todos.handle(&request("POST", "/todos", r#"{"title": "a", "done": true}"#)));
+        assert_eq!(unknown["status"], 422);
+        assert!(unknown["detail"].as_str().unwrap().contains("unknown field `done`"));
+    }
+}
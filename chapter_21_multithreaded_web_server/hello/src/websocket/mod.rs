@@ -0,0 +1,571 @@
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha1_smol::Sha1;
+
+use crate::http::{Request, Response, StatusCode};
+use crate::server::Upgraded;
+
+// WebSockets (RFC 6455): the client asks to upgrade an ordinary HTTP request, and after our
+// `101 Switching Protocols` both sides send each other messages over the same connection for as
+// long as they like. A route hands the connection over like this:
+//
+// "/live" => websocket::upgrade(request, &sessions, |mut socket| {
+//     while let Ok(message) = socket.recv() { ... }
+// })
+//
+// A WebSocket can stay open for hours, if each one held a pool worker a few browser tabs would
+// use up the whole pool. So the server gives each one a thread of its own instead, and `Sessions`
+// caps how many of those there can be.
+
+// Proves to the client that we actually speak WebSocket, see `accept_key`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    // We answer pings ourselves, they're passed on in case the handler cares.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    // The other side is closing the connection. We've answered it already, `recv` returns
+    // `WebSocketError::Closed` from now on.
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    // 1000 is a normal close, see RFC 6455 section 7.4 for the rest.
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    // The other side broke the protocol, we've closed the connection.
+    Protocol(&'static str),
+    // A message was bigger than `max_message_size`.
+    TooLarge,
+    // A text message that isn't UTF-8.
+    InvalidUtf8,
+    // The close handshake happened, there's nothing more to send or receive.
+    Closed,
+    Io(io::Error),
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebSocketError::Protocol(message) => write!(f, "WebSocket protocol error: {message}"),
+            WebSocketError::TooLarge => write!(f, "WebSocket message too large"),
+            WebSocketError::InvalidUtf8 => write!(f, "WebSocket text message is not valid UTF-8"),
+            WebSocketError::Closed => write!(f, "WebSocket is closed"),
+            WebSocketError::Io(e) => write!(f, "WebSocket I/O error: {e}"),
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> WebSocketError {
+        WebSocketError::Io(e)
+    }
+}
+
+impl WebSocketError {
+    // The status code we close the connection with, if there's still someone to tell.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Protocol(_) => Some(1002),
+            WebSocketError::InvalidUtf8 => Some(1007),
+            WebSocketError::TooLarge => Some(1009),
+            WebSocketError::Closed | WebSocketError::Io(_) => None,
+        }
+    }
+}
+
+// Frame opcodes.
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// Which end of the connection we are. Clients mask every frame they send and servers never do,
+// RFC 6455 makes both sides close the connection if that's not the case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Server,
+    Client,
+}
+
+// A WebSocket connection over any stream, usually the `Upgraded` one the server hands us.
+pub struct WebSocket<S> {
+    stream: S,
+    role: Role,
+    // A message made of more frames than fit in this is refused with a 1009 close.
+    max_message_size: usize,
+    // Messages longer than this are sent as several frames.
+    max_frame_size: usize,
+    // Whether we sent a close frame, after that we may not send anything else.
+    close_sent: bool,
+    close_received: bool,
+    // The opcode and the payload so far of a message that came in several frames. It's kept
+    // here and not in `read_message` because a ping between two fragments is returned before
+    // the message is finished.
+    fragmented: Option<(u8, Vec<u8>)>,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    pub fn server(stream: S) -> WebSocket<S> {
+        WebSocket::new(stream, Role::Server)
+    }
+
+    // For talking to a WebSocket server, e.g. ours from the tests.
+    pub fn client(stream: S) -> WebSocket<S> {
+        WebSocket::new(stream, Role::Client)
+    }
+
+    fn new(stream: S, role: Role) -> WebSocket<S> {
+        WebSocket {
+            stream,
+            role,
+            max_message_size: 1024 * 1024,
+            max_frame_size: 64 * 1024,
+            close_sent: false,
+            close_received: false,
+            fragmented: None,
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size.max(1);
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    // Waits for the next message. Fragmented messages come out whole, and pings are answered.
+    // When the other side breaks the protocol we close the connection with the matching code
+    // before returning the error.
+    pub fn recv(&mut self) -> Result<Message, WebSocketError> {
+        if self.close_received {
+            return Err(WebSocketError::Closed);
+        }
+        let result = self.read_message();
+        if let Some(code) = result.as_ref().err().and_then(WebSocketError::close_code) {
+            let _ = self.close(code, "");
+        }
+        result
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+        let (opcode, payload) = match message {
+            Message::Text(text) => (TEXT, text.into_bytes()),
+            Message::Binary(data) => (BINARY, data),
+            Message::Ping(data) => (PING, data),
+            Message::Pong(data) => (PONG, data),
+            Message::Close(frame) => {
+                let (code, reason) = frame.map_or((1000, String::new()), |frame| (frame.code, frame.reason));
+                return self.close(code, &reason);
+            }
+        };
+        if opcode >= CLOSE {
+            if payload.len() > 125 {
+                return Err(WebSocketError::TooLarge);
+            }
+            return self.write_frame(true, opcode, &payload);
+        }
+        // The first frame says what the message is, the rest are continuations.
+        let mut chunks = payload.chunks(self.max_frame_size).peekable();
+        let mut frame_opcode = opcode;
+        if chunks.peek().is_none() {
+            return self.write_frame(true, opcode, &[]);
+        }
+        while let Some(chunk) = chunks.next() {
+            self.write_frame(chunks.peek().is_none(), frame_opcode, chunk)?;
+            frame_opcode = CONTINUATION;
+        }
+        Ok(())
+    }
+
+    pub fn send_text(&mut self, text: impl Into<String>) -> Result<(), WebSocketError> {
+        self.send(Message::Text(text.into()))
+    }
+
+    // Starts the close handshake. The other side answers with a close frame of its own, which
+    // `recv` returns as `Message::Close`.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+        let mut payload = code.to_be_bytes().to_vec();
+        // A close frame's payload can't be longer than 125 bytes. The reason has to stay UTF-8,
+        // so we don't cut it in the middle of a character.
+        payload.extend_from_slice(&reason.as_bytes()[..reason.floor_char_boundary(123)]);
+        self.write_frame(true, CLOSE, &payload)
+    }
+
+    fn read_message(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            let (fin, opcode, payload) = self.read_frame()?;
+            match opcode {
+                // Control frames can come in between the fragments of a message.
+                PING => {
+                    if !self.close_sent {
+                        self.write_frame(true, PONG, &payload)?;
+                    }
+                    return Ok(Message::Ping(payload));
+                }
+                PONG => return Ok(Message::Pong(payload)),
+                CLOSE => return self.close_received(&payload),
+                TEXT | BINARY if self.fragmented.is_some() => {
+                    return Err(WebSocketError::Protocol("new message before the last one was finished"));
+                }
+                TEXT | BINARY if fin => return message(opcode, payload),
+                TEXT | BINARY => self.fragmented = Some((opcode, payload)),
+                CONTINUATION => {
+                    let Some((_, data)) = self.fragmented.as_mut() else {
+                        return Err(WebSocketError::Protocol("continuation frame without a message"));
+                    };
+                    if data.len() + payload.len() > self.max_message_size {
+                        return Err(WebSocketError::TooLarge);
+                    }
+                    data.extend_from_slice(&payload);
+                    if fin && let Some((opcode, data)) = self.fragmented.take() {
+                        return message(opcode, data);
+                    }
+                }
+                _ => return Err(WebSocketError::Protocol("unknown opcode")),
+            }
+        }
+    }
+
+    fn close_received(&mut self, payload: &[u8]) -> Result<Message, WebSocketError> {
+        self.close_received = true;
+        let frame = match payload {
+            [] => None,
+            [_] => return Err(WebSocketError::Protocol("close frame with a one-byte payload")),
+            [high, low, reason @ ..] => Some(CloseFrame {
+                code: u16::from_be_bytes([*high, *low]),
+                reason: String::from_utf8(reason.to_vec()).map_err(|_| WebSocketError::InvalidUtf8)?,
+            }),
+        };
+        // 1005, 1006 and 1015 only exist for reporting, they must never be sent, and the rest
+        // outside these ranges aren't assigned (RFC 6455 section 7.4).
+        if let Some(frame) = &frame
+            && !matches!(frame.code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+        {
+            return Err(WebSocketError::Protocol("invalid close code"));
+        }
+        // Answer with the same code, that completes the handshake.
+        self.close(frame.as_ref().map_or(1000, |frame| frame.code), "")?;
+        Ok(Message::Close(frame))
+    }
+
+    // One frame: 2 header bytes, an extended length if the payload is 126 bytes or longer, the
+    // masking key if the frame is masked, then the payload.
+    fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>), WebSocketError> {
+        let mut header = [0; 2];
+        self.stream.read_exact(&mut header)?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        // The reserved bits are for extensions, and we haven't agreed on any.
+        if header[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits set"));
+        }
+        let masked = header[1] & 0x80 != 0;
+        if masked != (self.role == Role::Server) {
+            return Err(WebSocketError::Protocol("wrong masking"));
+        }
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                self.stream.read_exact(&mut len)?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.stream.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => u64::from(len),
+        };
+        if opcode >= CLOSE && (!fin || len > 125) {
+            return Err(WebSocketError::Protocol("fragmented or oversized control frame"));
+        }
+        // Check before allocating, the length is whatever the other side says it is.
+        if len > self.max_message_size as u64 {
+            return Err(WebSocketError::TooLarge);
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            self.stream.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        if masked {
+            apply_mask(&mut payload, mask);
+        }
+        Ok((fin, opcode, payload))
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(if fin { 0x80 } else { 0 } | opcode);
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if self.role == Role::Client {
+            // The mask only has to be unpredictable to scripts in the browser, it's not about
+            // secrecy, so the standard library's randomly seeded hasher is good enough.
+            let mask = (RandomState::new().hash_one(frame.len()) as u32).to_be_bytes();
+            frame.extend_from_slice(&mask);
+            let start = frame.len();
+            frame.extend_from_slice(payload);
+            apply_mask(&mut frame[start..], mask);
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+fn message(opcode: u8, payload: Vec<u8>) -> Result<Message, WebSocketError> {
+    if opcode == TEXT {
+        String::from_utf8(payload).map(Message::Text).map_err(|_| WebSocketError::InvalidUtf8)
+    } else {
+        Ok(Message::Binary(payload))
+    }
+}
+
+// XORs every byte with the matching byte of the key. Masking and unmasking are the same thing.
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+// The `Sec-WebSocket-Accept` for the client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+// Counts the open WebSockets, so we can say no once there are `max` of them. Clones share the
+// count.
+#[derive(Clone)]
+pub struct Sessions {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl Sessions {
+    pub fn new(max: usize) -> Sessions {
+        Sessions { active: Arc::new(AtomicUsize::new(0)), max }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    fn try_acquire(&self) -> Option<Slot> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| (active < self.max).then_some(active + 1))
+            .ok()
+            .map(|_| Slot(Arc::clone(&self.active)))
+    }
+}
+
+// One taken place in `Sessions`, given back when it's dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Answers a WebSocket handshake request. If it's a valid one and there's room in `sessions`,
+// the response is a `101 Switching Protocols`, and once it's sent `on_open` gets the connection
+// (on a thread of its own). Otherwise it's the error response to send instead.
+pub fn upgrade<F>(request: &Request, sessions: &Sessions, on_open: F) -> Response
+where
+    F: FnOnce(WebSocket<Upgraded>) + Send + 'static,
+{
+    let has_token = |name: &str, token: &str| {
+        request
+            .header(name)
+            .is_some_and(|value| value.split(',').any(|value| value.trim().eq_ignore_ascii_case(token)))
+    };
+    if request.method != "GET" || !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Response::text(StatusCode::BadRequest, "Expected a WebSocket handshake");
+    }
+    // Version 13 is RFC 6455, older drafts aren't compatible.
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Response::new(StatusCode::UpgradeRequired).with_header("Sec-WebSocket-Version", "13");
+    }
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16) => key,
+        _ => return Response::text(StatusCode::BadRequest, "Missing or invalid Sec-WebSocket-Key"),
+    };
+    let Some(slot) = sessions.try_acquire() else {
+        return Response::text(StatusCode::ServiceUnavailable, "Too many WebSocket connections")
+            .with_header("Retry-After", "5");
+    };
+
+    Response::new(StatusCode::SwitchingProtocols)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key))
+        .with_upgrade(move |upgraded| {
+            // Held until the session is over.
+            let _slot = slot;
+            on_open(WebSocket::server(upgraded));
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Reads from `input`, writes to `output`.
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // What a client writes when it sends `messages`, in frames of at most `frame_size` bytes.
+    fn client_frames(messages: Vec<Message>, frame_size: usize) -> Vec<u8> {
+        let mut client = WebSocket::client(Pipe { input: Cursor::new(Vec::new()), output: Vec::new() });
+        client.set_max_frame_size(frame_size);
+        for message in messages {
+            client.send(message).unwrap();
+        }
+        client.stream.output
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn reads_masked_and_fragmented_messages() {
+        let input = client_frames(
+            vec![
+                Message::Text(String::from("hello, fragmented world")),
+                Message::Ping(b"are you there".to_vec()),
+                Message::Binary(vec![7; 300]),
+                Message::Close(Some(CloseFrame { code: 1000, reason: String::from("bye") })),
+            ],
+            5,
+        );
+        let mut server = WebSocket::server(Pipe { input: Cursor::new(input), output: Vec::new() });
+
+        assert_eq!(server.recv().unwrap(), Message::Text(String::from("hello, fragmented world")));
+        assert_eq!(server.recv().unwrap(), Message::Ping(b"are you there".to_vec()));
+        assert_eq!(server.recv().unwrap(), Message::Binary(vec![7; 300]));
+        assert_eq!(
+            server.recv().unwrap(),
+            Message::Close(Some(CloseFrame { code: 1000, reason: String::from("bye") }))
+        );
+        assert!(matches!(server.recv(), Err(WebSocketError::Closed)));
+
+        // We answered the ping with an unmasked pong, and the close with a close.
+        let mut client = WebSocket::client(Pipe { input: Cursor::new(server.stream.output), output: Vec::new() });
+        assert_eq!(client.recv().unwrap(), Message::Pong(b"are you there".to_vec()));
+        assert_eq!(client.recv().unwrap(), Message::Close(Some(CloseFrame { code: 1000, reason: String::new() })));
+    }
+
+    #[test]
+    fn pings_can_come_between_fragments() {
+        let mut client = WebSocket::client(Pipe { input: Cursor::new(Vec::new()), output: Vec::new() });
+        client.write_frame(false, TEXT, b"hel").unwrap();
+        client.write_frame(true, PING, b"?").unwrap();
+        client.write_frame(true, CONTINUATION, b"lo").unwrap();
+        let mut server = WebSocket::server(Pipe { input: Cursor::new(client.stream.output), output: Vec::new() });
+
+        assert_eq!(server.recv().unwrap(), Message::Ping(b"?".to_vec()));
+        assert_eq!(server.recv().unwrap(), Message::Text(String::from("hello")));
+    }
+
+    #[test]
+    fn close_codes_and_reasons_are_checked() {
+        // 1005 means "no code was sent", a peer can't send it.
+        let input = client_frames(vec![Message::Close(Some(CloseFrame { code: 1005, reason: String::new() }))], 125);
+        let mut server = WebSocket::server(Pipe { input: Cursor::new(input), output: Vec::new() });
+        assert!(matches!(server.recv(), Err(WebSocketError::Protocol(_))));
+        assert_eq!(server.stream.output, [0x88, 0x02, 0x03, 0xEA]);
+
+        // 61 two-byte characters are 122 bytes, the 62nd doesn't fit in 123.
+        let mut server = WebSocket::server(Pipe { input: Cursor::new(Vec::new()), output: Vec::new() });
+        server.close(1000, &"é".repeat(70)).unwrap();
+        assert_eq!(server.stream.output.len(), 2 + 2 + 122);
+    }
+
+    #[test]
+    fn unmasked_client_frames_are_a_protocol_error() {
+        // A server-side (unmasked) "hi".
+        let input = vec![0x81, 0x02, b'h', b'i'];
+        let mut server = WebSocket::server(Pipe { input: Cursor::new(input), output: Vec::new() });
+        assert!(matches!(server.recv(), Err(WebSocketError::Protocol(_))));
+        // Closed with 1002.
+        assert_eq!(server.stream.output, [0x88, 0x02, 0x03, 0xEA]);
+    }
+}
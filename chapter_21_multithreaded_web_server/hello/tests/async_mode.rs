@@ -0,0 +1,52 @@
+#![cfg(feature = "async")]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+// Reads one response with a `Content-Length` body off `reader`, returns its status line.
+fn read_response(reader: &mut BufReader<&TcpStream>) -> String {
+    let mut status = String::new();
+    reader.read_line(&mut status).unwrap();
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header == "\r\n" {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length: ") {
+            length = value.trim().parse().unwrap();
+        }
+    }
+    reader.read_exact(&mut vec![0; length]).unwrap();
+    status
+}
+
+#[test]
+fn idle_connections_dont_hold_workers() {
+    // Two runtime threads. In thread mode two idle keep-alive connections would be enough to
+    // keep everyone else waiting.
+    let (mut server, addr) = common::start_server(&["--mode", "async", "--workers", "2", "--keep-alive-timeout", "30"]);
+
+    let idle: Vec<TcpStream> = (0..200)
+        .map(|_| {
+            let stream = TcpStream::connect(addr).unwrap();
+            (&stream).write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            assert_eq!(read_response(&mut BufReader::new(&stream)), "HTTP/1.1 200 OK\r\n");
+            stream
+        })
+        .collect();
+
+    // All 200 are still open, and a pipelined pair of requests on a new connection gets served.
+    let stream = TcpStream::connect(addr).unwrap();
+    (&stream).write_all(b"GET / HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    let mut reader = BufReader::new(&stream);
+    assert_eq!(read_response(&mut reader), "HTTP/1.1 200 OK\r\n");
+    assert_eq!(read_response(&mut reader), "HTTP/1.1 404 Not Found\r\n");
+
+    drop(idle);
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,21 @@
+mod common;
+
+use std::process::Command;
+
+#[test]
+fn bench_reports_requests_per_second() {
+    let (mut server, addr) = common::start_server(&[]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_hello-bench"))
+        .args(["--connections", "4", "--duration", "1", &format!("http://{addr}/")])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("requests/s"), "{stdout}");
+    assert!(stdout.contains("Status codes: 200: "), "{stdout}");
+    assert!(stdout.contains("Errors:       0 "), "{stdout}");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,32 @@
+// Helpers for the tests that run the real `hello` binary.
+use std::io::{self, BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+// Starts the server on port 0, so the OS picks a free port and the tests don't need 7878 to be
+// free (or each other's ports). We find out which one it got from the "Listening on" line it
+// prints first. `args` are added after "--port 0".
+pub fn start_server(args: &[&str]) -> (Child, SocketAddr) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_hello"))
+        .args(["--port", "0"])
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    // Keep reading whatever else it prints, with the pipe closed its `println!`s would panic.
+    thread::spawn(move || io::copy(&mut stdout, &mut io::sink()));
+    let addr = line
+        .trim()
+        .strip_prefix("Listening on ")
+        .and_then(|url| url.strip_prefix("http://").or_else(|| url.strip_prefix("https://")))
+        .unwrap_or_else(|| panic!("unexpected first line {line:?}"))
+        .parse()
+        .unwrap();
+    (server, addr)
+}
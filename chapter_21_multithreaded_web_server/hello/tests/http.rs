@@ -0,0 +1,78 @@
+mod common;
+
+use hello::client::Client;
+
+#[test]
+fn serves_pages_over_keep_alive() {
+    let (mut server, addr) = common::start_server(&[]);
+    let mut client = Client::new(addr).unwrap();
+
+    let page = client.get("/").unwrap();
+    assert_eq!(page.status, 200);
+    assert_eq!(page.header("Content-Type"), Some("text/html; charset=utf-8"));
+    assert!(page.text().contains("Hello!"));
+
+    let missing = client.get("/nope").unwrap();
+    assert_eq!(missing.status, 404);
+    assert!(missing.text().contains("Oops!"));
+    // Both on the same connection.
+    assert_eq!(client.connections_opened(), 1);
+
+    // After `Connection: close` the next request needs a new one.
+    let closing = client.request("GET", "/", &[("Connection", "close")], &[]).unwrap();
+    assert_eq!(closing.header("Connection"), Some("close"));
+    client.get("/").unwrap();
+    assert_eq!(client.connections_opened(), 2);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn head_responses_have_no_body() {
+    let modes: &[&str] = if cfg!(feature = "async") { &["threads", "async"] } else { &["threads"] };
+    for mode in modes {
+        let (mut server, addr) = common::start_server(&["--mode", mode]);
+        let mut client = Client::new(addr).unwrap();
+
+        let head = client.request("HEAD", "/nope", &[], &[]).unwrap();
+        assert_eq!(head.status, 404);
+        assert!(head.body.is_empty());
+        // If the body had come anyway, this would read it as the next response.
+        let page = client.get("/nope").unwrap();
+        assert_eq!(page.status, 404);
+        assert_eq!(head.header("Content-Length"), Some(page.body.len().to_string().as_str()));
+        assert_eq!(client.connections_opened(), 1);
+
+        server.kill().unwrap();
+        server.wait().unwrap();
+    }
+}
+
+#[test]
+fn middlewares_apply_to_real_responses() {
+    let (mut server, addr) = common::start_server(&["--auth-token", "s3cret", "--cors-origin", "https://example.com"]);
+    let mut client = Client::new(addr).unwrap();
+
+    let denied = client.get("/").unwrap();
+    assert_eq!(denied.status, 401);
+    assert_eq!(denied.header("WWW-Authenticate"), Some("Bearer"));
+
+    let page = client
+        .request(
+            "GET",
+            "/",
+            &[("Authorization", "Bearer s3cret"), ("Origin", "https://example.com"), ("Accept-Encoding", "gzip")],
+            &[],
+        )
+        .unwrap();
+    assert_eq!(page.status, 200);
+    assert_eq!(page.header("Access-Control-Allow-Origin"), Some("https://example.com"));
+    // hello.html is too small to be worth compressing, but caches still need to know it could
+    // have been, and that the CORS headers depend on the origin.
+    let vary: Vec<&str> = page.headers.iter().filter(|(name, _)| *name == "Vary").map(|(_, value)| value).collect();
+    assert_eq!(vary, ["Origin", "Accept-Encoding"]);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use hello::client::{Client, ClientResponse};
+
+#[test]
+fn caps_connections_per_client() {
+    let (mut server, addr) = common::start_server(&["--max-connections-per-ip", "2"]);
+
+    // Two idle connections are all we get...
+    let first = TcpStream::connect(addr).unwrap();
+    let _second = TcpStream::connect(addr).unwrap();
+    let third = TcpStream::connect(addr).unwrap();
+    let refused = ClientResponse::read_from(&mut BufReader::new(third), false).unwrap();
+    assert_eq!(refused.status, 429);
+    assert_eq!(refused.header("Retry-After"), Some("1"));
+
+    // ...until one of them closes.
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(Client::new(addr).unwrap().get("/").unwrap().status, 200);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn upgraded_connections_still_count() {
+    let modes: &[&str] = if cfg!(feature = "async") { &["threads", "async"] } else { &["threads"] };
+    for mode in modes {
+        let (mut server, addr) = common::start_server(&["--mode", mode, "--max-connections-per-ip", "1"]);
+
+        let mut websocket = TcpStream::connect(addr).unwrap();
+        write!(
+            websocket,
+            "GET /echo HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+        let mut status_line = String::new();
+        BufReader::new(&websocket).read_line(&mut status_line).unwrap();
+        assert!(status_line.starts_with("HTTP/1.1 101"), "{status_line}");
+
+        // The WebSocket left the HTTP code behind, but it's still this client's one connection.
+        let refused = ClientResponse::read_from(&mut BufReader::new(TcpStream::connect(addr).unwrap()), false).unwrap();
+        assert_eq!(refused.status, 429);
+
+        server.kill().unwrap();
+        server.wait().unwrap();
+    }
+}
+
+#[test]
+fn limits_request_rate_per_client() {
+    let (mut server, addr) = common::start_server(&["--rate-limit", "2"]);
+    let mut client = Client::new(addr).unwrap();
+
+    assert_eq!(client.get("/").unwrap().status, 200);
+    assert_eq!(client.get("/").unwrap().status, 200);
+    let limited = client.get("/").unwrap();
+    assert_eq!(limited.status, 429);
+    assert_eq!(limited.header("Retry-After"), Some("1"));
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use hello::client::Client;
+
+#[test]
+fn proxies_to_another_server() {
+    let (mut upstream, upstream_addr) = common::start_server(&[]);
+    let (mut server, addr) = common::start_server(&["--proxy", &format!("/={upstream_addr}")]);
+    let mut client = Client::new(addr).unwrap();
+
+    let page = client.request("GET", "/", &[("Accept-Encoding", "gzip")], &[]).unwrap();
+    assert_eq!(page.status, 200);
+    assert!(page.text().contains("Hello!"));
+    // Both servers' Gzip said it, the client hears it once.
+    assert_eq!(page.headers.iter().filter(|(name, _)| *name == "Vary").count(), 1);
+    let missing = client.get("/nope").unwrap();
+    assert_eq!(missing.status, 404);
+    assert!(missing.text().contains("Oops!"));
+    // Every request was a new connection upstream, ours stayed open.
+    assert_eq!(client.connections_opened(), 1);
+
+    upstream.kill().unwrap();
+    upstream.wait().unwrap();
+    let unavailable = client.get("/").unwrap();
+    assert_eq!(unavailable.status, 503);
+    assert!(unavailable.header("Retry-After").is_some());
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,72 @@
+// These tests run the real `hello` binary, send it a signal and check that it shuts down on its
+// own instead of having to be killed.
+#![cfg(unix)]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::start_server;
+
+fn send_signal(server: &Child, signal: &str) {
+    let status = Command::new("kill")
+        .args([signal, &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+fn wait_for_exit(server: &mut Child, timeout: Duration) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if let Some(status) = server.try_wait().unwrap() {
+            return status.success();
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    server.kill().unwrap();
+    false
+}
+
+#[test]
+fn exits_cleanly_on_signals() {
+    for signal in ["-INT", "-TERM"] {
+        let (mut server, addr) = start_server(&[]);
+
+        // An idle keep-alive connection must not keep the server alive.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut status_line = String::new();
+        BufReader::new(&stream).read_line(&mut status_line).unwrap();
+        assert_eq!(status_line, "HTTP/1.1 200 OK\r\n");
+
+        send_signal(&server, signal);
+        assert!(
+            wait_for_exit(&mut server, Duration::from_secs(5)),
+            "server didn't exit cleanly after {signal}"
+        );
+    }
+}
+
+#[test]
+fn port_in_use_is_a_friendly_error() {
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port().to_string();
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_hello"))
+        .args(["--port", &port])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = String::new();
+    server.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+
+    assert!(!server.wait().unwrap().success());
+    assert!(stderr.contains("already in use"), "unexpected error: {stderr}");
+    assert!(!stderr.contains("panicked"), "unexpected error: {stderr}");
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use std::env;
+use std::fs::{self, File};
+use std::time::{Duration, SystemTime};
+
+use hello::client::Client;
+
+#[test]
+fn renders_pages_and_reloads_them_in_dev_mode() {
+    let root = env::temp_dir().join(format!("hello-templates-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("head.html"), "<title>{{ title }}</title>\n").unwrap();
+    fs::write(root.join("hello.html"), "{% include \"head.html\" %}<p>first</p>").unwrap();
+    fs::write(root.join("404.html"), "<p>No {{ path }} here</p>").unwrap();
+
+    let (mut server, addr) = common::start_server(&["--root", root.to_str().unwrap(), "--dev"]);
+    let mut client = Client::new(addr).unwrap();
+    assert_eq!(client.get("/").unwrap().text(), "<title>Hello!</title>\n<p>first</p>");
+    // The path is whatever the client sent, it must not become markup.
+    assert_eq!(client.get("/<b>").unwrap().text(), "<p>No /&lt;b&gt; here</p>");
+
+    fs::write(root.join("hello.html"), "<p>second</p>").unwrap();
+    // Some file systems only keep whole seconds, make sure the change shows.
+    File::options()
+        .write(true)
+        .open(root.join("hello.html"))
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(2))
+        .unwrap();
+    assert_eq!(client.get("/").unwrap().text(), "<p>second</p>");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    fs::remove_dir_all(&root).unwrap();
+}
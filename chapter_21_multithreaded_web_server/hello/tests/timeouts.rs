@@ -0,0 +1,52 @@
+// Clients that send their request too slowly or too big get an error instead of a worker.
+#![cfg(unix)]
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use common::start_server;
+
+// Sends `raw` and returns everything the server answers until it closes the connection.
+fn exchange(stream: &mut TcpStream, raw: &[u8]) -> String {
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(raw).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn slow_client_gets_408_and_frees_its_worker() {
+    // A single worker, so if the slow client kept it nobody else would get an answer.
+    let (mut server, addr) = start_server(&["--workers", "1", "--request-timeout", "1"]);
+
+    let started = Instant::now();
+    let mut slow = TcpStream::connect(addr).unwrap();
+    // The start of a request, but never the end of it.
+    let response = exchange(&mut slow, b"GET / HTTP/1.1\r\nHost: loc");
+    assert!(response.starts_with("HTTP/1.1 408"), "unexpected response {response:?}");
+    assert!(started.elapsed() < Duration::from_secs(3));
+
+    let mut next = TcpStream::connect(addr).unwrap();
+    let response = exchange(&mut next, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "unexpected response {response:?}");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn oversized_headers_get_431() {
+    let (mut server, addr) = start_server(&["--max-header-size", "128"]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let raw = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(200));
+    let response = exchange(&mut stream, raw.as_bytes());
+    assert!(response.starts_with("HTTP/1.1 431"), "unexpected response {response:?}");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,48 @@
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+#[test]
+fn serves_https_with_a_self_signed_certificate() {
+    // A fresh certificate for "localhost", so there's no key checked into the repo.
+    let generated = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let dir = std::env::temp_dir().join(format!("hello-tls-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&cert_path, generated.cert.pem()).unwrap();
+    fs::write(&key_path, generated.key_pair.serialize_pem()).unwrap();
+
+    let (mut server, addr) = common::start_server(&[
+        "--tls-cert",
+        cert_path.to_str().unwrap(),
+        "--tls-key",
+        key_path.to_str().unwrap(),
+    ]);
+
+    // The client trusts exactly that certificate.
+    let mut roots = RootCertStore::empty();
+    roots.add(generated.cert.der().clone()).unwrap();
+    let client_config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let session = ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap();
+    let mut stream = StreamOwned::new(session, TcpStream::connect(addr).unwrap());
+
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    // This also checks that the server ends the session properly, rustls treats a connection
+    // closed without a close_notify as an error.
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("Hello!"), "{response}");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use hello::client::{Client, ClientResponse};
+use hello::todos::Todo;
+
+const JSON: (&str, &str) = ("Content-Type", "application/json");
+
+fn todo(response: &ClientResponse) -> Todo {
+    serde_json::from_slice(&response.body).unwrap()
+}
+
+#[test]
+fn todos_crud() {
+    let (mut server, addr) = common::start_server(&[]);
+    let mut client = Client::new(addr).unwrap();
+
+    let created = client.request("POST", "/todos", &[JSON], br#"{"title": " Write tests "}"#).unwrap();
+    assert_eq!(created.status, 201);
+    assert_eq!(created.header("Content-Type"), Some("application/json"));
+    assert_eq!(created.header("Location"), Some("/todos/1"));
+    assert_eq!(todo(&created), Todo { id: 1, title: String::from("Write tests"), completed: false });
+    client.request("POST", "/todos", &[JSON], br#"{"title": "Ship it"}"#).unwrap();
+
+    let patched = client.request("PATCH", "/todos/1", &[JSON], br#"{"completed": true}"#).unwrap();
+    assert_eq!(patched.status, 200);
+    assert!(todo(&patched).completed);
+    let replaced = client.request("PUT", "/todos/2", &[JSON], br#"{"title": "Ship it now"}"#).unwrap();
+    assert_eq!(todo(&replaced).title, "Ship it now");
+
+    let deleted = client.request("DELETE", "/todos/1", &[], &[]).unwrap();
+    assert_eq!(deleted.status, 204);
+    let list: Vec<Todo> = serde_json::from_slice(&client.get("/todos").unwrap().body).unwrap();
+    assert_eq!(list, [Todo { id: 2, title: String::from("Ship it now"), completed: false }]);
+
+    let missing = client.get("/todos/1").unwrap();
+    assert_eq!(missing.status, 404);
+    assert_eq!(missing.header("Content-Type"), Some("application/problem+json"));
+    let not_json = client.request("POST", "/todos", &[("Content-Type", "text/plain")], b"title").unwrap();
+    assert_eq!(not_json.status, 415);
+    let broken = client.request("POST", "/todos", &[JSON], b"{\"title\":").unwrap();
+    assert_eq!(broken.status, 400);
+    let invalid = client.request("POST", "/todos", &[JSON], br#"{"title": ""}"#).unwrap();
+    assert_eq!(invalid.status, 422);
+    let problem: serde_json::Value = serde_json::from_slice(&invalid.body).unwrap();
+    assert_eq!(problem["errors"]["title"], "can't be empty");
+    let not_allowed = client.request("DELETE", "/todos", &[], &[]).unwrap();
+    assert_eq!(not_allowed.status, 405);
+    assert_eq!(not_allowed.header("Allow"), Some("GET, POST"));
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
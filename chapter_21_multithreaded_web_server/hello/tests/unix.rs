@@ -0,0 +1,68 @@
+// The server on a Unix domain socket (`--unix`) instead of a TCP port.
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Like `common::start_server`, but the first line names the socket file instead of a port.
+fn start_server(path: &Path) -> Child {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_hello"))
+        .args(["--unix", path.to_str().unwrap(), "--unix-mode", "600"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line.trim(), format!("Listening on unix:{}", path.display()));
+    thread::spawn(move || std::io::copy(&mut stdout, &mut std::io::sink()));
+    server
+}
+
+#[test]
+fn serves_over_a_unix_socket() {
+    let dir = env::temp_dir().join(format!("hello-unix-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("hello.sock");
+    // What a server that was killed leaves behind: the file, but nobody listening on it.
+    drop(UnixListener::bind(&path).unwrap());
+
+    let mut server = start_server(&path);
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    // The directory it got that mode in is gone.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+    // A socket someone still answers on isn't stale, a second server must leave it alone.
+    let second = Command::new(env!("CARGO_BIN_EXE_hello"))
+        .args(["--unix", path.to_str().unwrap()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!second.success());
+
+    // On a clean shutdown the socket file goes away with the server.
+    Command::new("kill").args(["-TERM", &server.id().to_string()]).status().unwrap();
+    let started = Instant::now();
+    while server.try_wait().unwrap().is_none() {
+        assert!(started.elapsed() < Duration::from_secs(10), "the server didn't exit");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!path.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+use hello::websocket::{CloseFrame, Message, WebSocket};
+
+#[test]
+fn echoes_websocket_messages() {
+    let (mut server, addr) = common::start_server(&[]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET /echo HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )
+    .unwrap();
+    // Read the response head a byte at a time, so no frame bytes end up in a buffer.
+    let mut reader = BufReader::with_capacity(1, &stream);
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        reader.read_line(&mut head).unwrap();
+    }
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{head}");
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{head}");
+
+    let mut socket = WebSocket::client(stream);
+    socket.send_text("hello").unwrap();
+    assert_eq!(socket.recv().unwrap(), Message::Text(String::from("hello")));
+    // Bigger than a frame, so it goes out in pieces.
+    socket.set_max_frame_size(1000);
+    socket.send(Message::Binary(vec![42; 100_000])).unwrap();
+    assert_eq!(socket.recv().unwrap(), Message::Binary(vec![42; 100_000]));
+    socket.send(Message::Ping(b"ping".to_vec())).unwrap();
+    assert_eq!(socket.recv().unwrap(), Message::Pong(b"ping".to_vec()));
+
+    socket.close(1000, "done").unwrap();
+    assert_eq!(socket.recv().unwrap(), Message::Close(Some(CloseFrame { code: 1000, reason: String::new() })));
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn plain_requests_to_a_websocket_route_are_refused() {
+    let (mut server, addr) = common::start_server(&[]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /echo HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    let mut status = String::new();
+    BufReader::new(&stream).read_line(&mut status).unwrap();
+    assert_eq!(status, "HTTP/1.1 400 Bad Request\r\n");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}